@@ -0,0 +1,178 @@
+//! # Allowlist of scripts
+//!
+//! An optional allowlist pins the content of every script which may run by its SHA-256 digest.
+//! It has the format of `sha256sum`, one `<digest>  <path>` per line, so it can be checked with
+//! `sha256sum --check` too. A script which is not listed, or whose content does not match its
+//! digest, is refused with an audit log entry, which has the journal field `NWD_AUDIT=refused`.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    Context,
+    Error,
+    Result,
+    bail,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use tracing::warn;
+
+/// Approved SHA-256 digest of each script path
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Allowlist {
+    digests: HashMap<PathBuf, String>,
+}
+
+impl Allowlist {
+    pub fn load(path: &Path) -> Result<Allowlist> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read allowlist `{}`", path.display()))?;
+        Allowlist::parse(&content)
+            .with_context(|| format!("Invalid allowlist `{}`", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Allowlist> {
+        let mut digests = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((digest, path)) = line.split_once(' ') else {
+                bail!("Line {} is not `<digest>  <path>`", number + 1);
+            };
+            // `sha256sum` marks a path with ` ` in text mode, or `*` in binary mode
+            let path = PathBuf::from(
+                path.strip_prefix(' ')
+                    .or_else(|| path.strip_prefix('*'))
+                    .unwrap_or(path),
+            );
+            if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+                bail!(
+                    "Line {} has an invalid SHA-256 digest `{digest}`",
+                    number + 1
+                );
+            }
+            if !path.is_absolute() {
+                bail!(
+                    "Line {} has a relative path `{}`",
+                    number + 1,
+                    path.display()
+                );
+            }
+            digests.insert(path, digest.to_ascii_lowercase());
+        }
+        Ok(Allowlist { digests })
+    }
+
+    /// Allowlist of the current content of files, in the format of the allowlist file
+    pub fn generate<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Result<String> {
+        let mut allowlist = String::new();
+        for path in paths {
+            writeln!(allowlist, "{}  {}", sha256_file(path)?, path.display())?;
+        }
+        Ok(allowlist)
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.digests.contains_key(path)
+    }
+
+    /// Check the current content of a file, return its digest
+    pub fn verify(&self, path: &Path) -> Result<String> {
+        let content =
+            fs::read(path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+        self.verify_content(path, &content)
+    }
+
+    /// Check content which is read from a file, return its digest
+    pub fn verify_content(&self, path: &Path, content: &[u8]) -> Result<String> {
+        let Some(expected) = self.digests.get(path) else {
+            bail!("It is not in the allowlist");
+        };
+        let digest = sha256(content);
+        if digest != *expected {
+            bail!("Its SHA-256 digest {digest} does not match {expected} of the allowlist");
+        }
+        Ok(digest)
+    }
+}
+
+/// SHA-256 digest of a file, in lowercase hex
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let content = fs::read(path).with_context(|| format!("Failed to read `{}`", path.display()))?;
+    Ok(sha256(&content))
+}
+
+fn sha256(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Emit an audit log entry of a script or configuration file which is refused
+pub fn audit_refused(path: &Path, err: &Error) {
+    warn!(
+        nwd_audit = "refused",
+        nwd_script = %path.display(),
+        "`{}` is refused. {err:#}",
+        path.display()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let temp_dir = TempDir::new().unwrap();
+        let approved = temp_dir.path().join("00-approved");
+        let modified = temp_dir.path().join("01-modified");
+        let unlisted = temp_dir.path().join("02-unlisted");
+        for script in [&approved, &modified, &unlisted] {
+            fs::write(script, "#!/usr/bin/sh\necho up\n").unwrap();
+        }
+
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let content = Allowlist::generate([approved.as_path(), modified.as_path()]).unwrap();
+        let expected = sha256(b"#!/usr/bin/sh\necho up\n");
+        assert_eq!(
+            content,
+            format!(
+                "{expected}  {}\n{expected}  {}\n",
+                approved.display(),
+                modified.display()
+            )
+        );
+
+        let allowlist = Allowlist::parse(&format!("# Approved scripts\n{content}")).unwrap();
+        fs::write(&modified, "#!/usr/bin/sh\necho down\n").unwrap();
+        assert_eq!(allowlist.verify(&approved).unwrap(), expected);
+        assert!(allowlist.verify(&modified).is_err());
+        assert!(allowlist.verify(&unlisted).is_err());
+
+        // Binary mode marker of `sha256sum`
+        let digest = "0".repeat(64);
+        let allowlist = Allowlist::parse(&format!("{digest} */etc/script\n")).unwrap();
+        assert!(allowlist.digests.contains_key(Path::new("/etc/script")));
+
+        assert!(Allowlist::parse("not-a-digest  /etc/script").is_err());
+        assert!(Allowlist::parse(&format!("{digest}  etc/script")).is_err());
+        assert!(Allowlist::parse(&digest).is_err());
+    }
+}
@@ -1,19 +1,62 @@
-use std::path::PathBuf;
+use std::{
+    num::NonZeroUsize,
+    path::PathBuf,
+    time::Duration,
+};
 
-use clap::Parser;
+use anyhow::Result;
+use clap::{
+    Parser,
+    Subcommand,
+};
 
-use crate::script::DEFAULT_TIMEOUT;
+use crate::{
+    compat::Compat,
+    config::DEFAULT_CONFIG_FILE,
+    flap::{
+        DEFAULT_FLAP_COOLDOWN,
+        DEFAULT_FLAP_WINDOW,
+        FlapDetection,
+    },
+    launcher::{
+        DEFAULT_MAX_CONCURRENCY,
+        SupersedePolicy,
+    },
+    run_as::find_user,
+    script::{
+        DEFAULT_GRACE,
+        DEFAULT_TIMEOUT,
+    },
+    script_output::OutputMode,
+    settle::SettleArg,
+};
+
+/// Script root directories in priority order, vendor scripts are overridden by local ones
+pub const DEFAULT_SCRIPT_DIRS: [&str; 3] = [
+    "/usr/lib/networkd/broker.d",
+    "/run/networkd/broker.d",
+    "/etc/networkd/broker.d",
+];
 
 #[derive(PartialEq, Eq, Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Arguments {
-    /// Location under which to look for scripts
-    #[arg(
-        short = 'S',
-        long = "script-dir",
-        default_value = "/etc/networkd/broker.d"
-    )]
-    pub script_dir: PathBuf,
+    /// Location under which to look for scripts. Can be repeated, scripts of a later location
+    /// override those of the same name in an earlier one
+    /// [default: /usr/lib/networkd/broker.d, /run/networkd/broker.d and /etc/networkd/broker.d,
+    /// or /usr/lib/networkd-dispatcher and /etc/networkd-dispatcher with
+    /// `--compat networkd-dispatcher`]
+    #[arg(short = 'S', long = "script-dir")]
+    pub script_dir: Vec<PathBuf>,
+
+    /// Configuration file with hook rules, it is optional
+    #[arg(short = 'c', long = "config", default_value = DEFAULT_CONFIG_FILE)]
+    pub config: PathBuf,
+
+    /// Allowlist of SHA-256 digests of scripts and the configuration file. Anything which is
+    /// not listed or is modified is refused to run.
+    #[arg(long = "allowlist", value_name = "PATH")]
+    pub allowlist: Option<PathBuf>,
 
     /// Generate events reflecting preexisting state and behavior on startup
     #[arg(short = 'T', long = "startup-triggers")]
@@ -22,6 +65,97 @@ pub struct Arguments {
     /// Script execution timeout in seconds
     #[arg(short = 't', long = "timeout", default_value_t = DEFAULT_TIMEOUT)]
     pub timeout: u64,
+
+    /// Seconds to wait after a timed out script is sent its kill signal, before its process
+    /// group is killed with SIGKILL
+    #[arg(long = "grace", default_value_t = DEFAULT_GRACE)]
+    pub grace: u64,
+
+    /// What to do with standard output and standard error of scripts
+    #[arg(long = "script-output", value_enum, default_value_t = OutputMode::Forward)]
+    pub script_output: OutputMode,
+
+    /// Run scripts of another dispatcher with its directory layout and environment variables
+    #[arg(long = "compat", value_enum)]
+    pub compat: Option<Compat>,
+
+    /// Maximum number of scripts running at the same time. Scripts of one link always run one
+    /// by one. Nowait scripts are not counted once they are started.
+    #[arg(long = "max-concurrency", default_value_t = DEFAULT_MAX_CONCURRENCY)]
+    pub max_concurrency: NonZeroUsize,
+
+    /// What to do with scripts of older events of a link when a newer event arrives
+    #[arg(long = "supersede", value_enum, default_value_t = SupersedePolicy::RunAll)]
+    pub supersede: SupersedePolicy,
+
+    /// Seconds a changed link state has to persist before scripts are run for it, as
+    /// `<seconds>` for every state or `<state>=<seconds>` for one state. Can be repeated.
+    #[arg(long = "settle", value_name = "[STATE=]SECONDS")]
+    pub settle: Vec<SettleArg>,
+
+    /// Number of transitions within `--flap-window` above which a link is flapping.
+    /// Flap detection is disabled by default.
+    #[arg(long = "flap-threshold")]
+    pub flap_threshold: Option<usize>,
+
+    /// Window in seconds in which transitions of a link are counted for flap detection
+    #[arg(long = "flap-window", default_value_t = DEFAULT_FLAP_WINDOW)]
+    pub flap_window: u64,
+
+    /// Seconds without a transition before a flapping link is stable again
+    #[arg(long = "flap-cooldown", default_value_t = DEFAULT_FLAP_COOLDOWN)]
+    pub flap_cooldown: u64,
+
+    /// Run scripts which are owned by this user, as the user. Can be repeated.
+    #[arg(long = "allowed-owner", value_name = "USER")]
+    pub allowed_owners: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(PartialEq, Eq, Debug, Subcommand)]
+pub enum Command {
+    /// Print an allowlist of the scripts and the configuration file as they are now, for
+    /// `--allowlist`
+    GenerateAllowlist {
+        /// Write the allowlist to a file instead of standard output
+        #[arg(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+}
+
+impl Arguments {
+    /// Locations under which to look for scripts in priority order, with the default of
+    /// compatibility mode
+    pub fn script_root_dirs(&self) -> Vec<PathBuf> {
+        if !self.script_dir.is_empty() {
+            return self.script_dir.clone();
+        }
+        let defaults = match self.compat {
+            Some(compat) => compat.default_script_dirs(),
+            None => &DEFAULT_SCRIPT_DIRS,
+        };
+        defaults.iter().map(PathBuf::from).collect()
+    }
+
+    /// User IDs of allowed owners of scripts
+    pub fn allowed_owners(&self) -> Result<Vec<u32>> {
+        self.allowed_owners
+            .iter()
+            .map(|user| find_user(user).map(|user| user.uid.as_raw()))
+            .collect()
+    }
+
+    pub fn flap_detection(&self) -> Option<FlapDetection> {
+        self.flap_threshold.map(|threshold| {
+            FlapDetection::new(
+                threshold,
+                Duration::from_secs(self.flap_window),
+                Duration::from_secs(self.flap_cooldown),
+            )
+        })
+    }
 }
 
 #[cfg(test)]
@@ -40,36 +174,166 @@ mod tests {
             &Arguments::command().get_matches_from(vec![env!("CARGO_CRATE_NAME")]),
         )
         .expect("Paring argument");
-        assert_eq!(args.script_dir, PathBuf::from("/etc/networkd/broker.d"));
+        assert_eq!(
+            args.script_root_dirs(),
+            [
+                PathBuf::from("/usr/lib/networkd/broker.d"),
+                PathBuf::from("/run/networkd/broker.d"),
+                PathBuf::from("/etc/networkd/broker.d"),
+            ]
+        );
+        assert_eq!(args.config, PathBuf::from("/etc/networkd/broker.conf"));
         assert!(!args.startup_triggers);
         assert_eq!(args.timeout, DEFAULT_TIMEOUT);
+        assert_eq!(args.compat, None);
+        assert_eq!(args.grace, DEFAULT_GRACE);
+        assert_eq!(args.script_output, OutputMode::Forward);
+        assert_eq!(args.max_concurrency, DEFAULT_MAX_CONCURRENCY);
+        assert_eq!(args.supersede, SupersedePolicy::RunAll);
+        assert!(args.settle.is_empty());
+        assert_eq!(args.flap_detection(), None);
+        assert!(args.allowed_owners().unwrap().is_empty());
+        assert_eq!(args.allowlist, None);
+        assert_eq!(args.command, None);
 
         // Full long arguments
         let args = Arguments::from_arg_matches(&Arguments::command().get_matches_from(vec![
             env!("CARGO_CRATE_NAME"),
             "--script-dir",
             "/etc/networkd/broker2.d",
+            "--config",
+            "/etc/networkd/broker2.conf",
+            "--allowlist",
+            "/etc/networkd/broker.allowlist",
             "--startup-triggers",
             "--timeout",
             "50",
+            "--grace",
+            "10",
+            "--script-output",
+            "on-failure",
+            "--max-concurrency",
+            "8",
+            "--supersede",
+            "cancel-running",
+            "--settle",
+            "2",
+            "--settle",
+            "degraded=10s",
+            "--flap-threshold",
+            "5",
+            "--flap-window",
+            "30",
+            "--allowed-owner",
+            "root",
+            "--allowed-owner",
+            "65534",
         ]))
         .expect("Paring argument");
-        assert_eq!(args.script_dir, PathBuf::from("/etc/networkd/broker2.d"));
+        assert_eq!(
+            args.script_root_dirs(),
+            [PathBuf::from("/etc/networkd/broker2.d")]
+        );
+        assert_eq!(args.config, PathBuf::from("/etc/networkd/broker2.conf"));
         assert!(args.startup_triggers);
         assert_eq!(args.timeout, 50);
+        assert_eq!(args.grace, 10);
+        assert_eq!(args.script_output, OutputMode::OnFailure);
+        assert_eq!(args.max_concurrency.get(), 8);
+        assert_eq!(args.supersede, SupersedePolicy::CancelRunning);
+        assert_eq!(
+            args.settle,
+            [
+                SettleArg {
+                    state: None,
+                    delay: Duration::from_secs(2),
+                },
+                SettleArg {
+                    state: Some("degraded".to_string()),
+                    delay: Duration::from_secs(10),
+                },
+            ]
+        );
+        assert_eq!(
+            args.flap_detection(),
+            Some(FlapDetection::new(
+                5,
+                Duration::from_secs(30),
+                Duration::from_secs(DEFAULT_FLAP_COOLDOWN)
+            ))
+        );
+        assert_eq!(args.allowed_owners().unwrap(), [0, 65534]);
+        assert_eq!(
+            args.allowlist,
+            Some(PathBuf::from("/etc/networkd/broker.allowlist"))
+        );
+
+        // Generate allowlist
+        let args = Arguments::from_arg_matches(&Arguments::command().get_matches_from(vec![
+            env!("CARGO_CRATE_NAME"),
+            "-S",
+            "/etc/networkd/broker2.d",
+            "generate-allowlist",
+            "-o",
+            "/etc/networkd/broker.allowlist",
+        ]))
+        .expect("Paring argument");
+        assert_eq!(
+            args.command,
+            Some(Command::GenerateAllowlist {
+                output: Some(PathBuf::from("/etc/networkd/broker.allowlist")),
+            })
+        );
 
         // Full short arguments
         let args = Arguments::from_arg_matches(&Arguments::command().get_matches_from(vec![
             env!("CARGO_CRATE_NAME"),
             "-S",
+            "/usr/lib/networkd/broker2.d",
+            "-S",
             "/etc/networkd/broker2.d",
             "-T",
             "-t",
             "50",
         ]))
         .expect("Paring argument");
-        assert_eq!(args.script_dir, PathBuf::from("/etc/networkd/broker2.d"));
+        assert_eq!(
+            args.script_root_dirs(),
+            [
+                PathBuf::from("/usr/lib/networkd/broker2.d"),
+                PathBuf::from("/etc/networkd/broker2.d"),
+            ]
+        );
         assert!(args.startup_triggers);
         assert_eq!(args.timeout, 50);
+
+        // Compatibility mode
+        let args = Arguments::from_arg_matches(&Arguments::command().get_matches_from(vec![
+            env!("CARGO_CRATE_NAME"),
+            "--compat",
+            "networkd-dispatcher",
+        ]))
+        .expect("Paring argument");
+        assert_eq!(args.compat, Some(Compat::NetworkdDispatcher));
+        assert_eq!(
+            args.script_root_dirs(),
+            [
+                PathBuf::from("/usr/lib/networkd-dispatcher"),
+                PathBuf::from("/etc/networkd-dispatcher"),
+            ]
+        );
+
+        let args = Arguments::from_arg_matches(&Arguments::command().get_matches_from(vec![
+            env!("CARGO_CRATE_NAME"),
+            "--compat",
+            "networkd-dispatcher",
+            "-S",
+            "/etc/networkd/broker2.d",
+        ]))
+        .expect("Paring argument");
+        assert_eq!(
+            args.script_root_dirs(),
+            [PathBuf::from("/etc/networkd/broker2.d")]
+        );
     }
 }
@@ -1,7 +1,12 @@
 use std::{
     collections::BTreeMap,
     path::PathBuf,
+    pin::pin,
     sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use anyhow::{
@@ -9,11 +14,27 @@ use anyhow::{
     Result,
     bail,
 };
-use futures_util::stream::StreamExt;
+use async_io::Timer;
+use async_signal::{
+    Signal,
+    Signals,
+};
+use futures_util::{
+    FutureExt,
+    future,
+    stream::{
+        self,
+        StreamExt,
+    },
+};
 use libsystemd::daemon::{
     self,
     NotifyState,
 };
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use tracing::{
     debug,
     error,
@@ -25,67 +46,272 @@ use zbus::{
     MatchRule,
     Message,
     MessageStream,
+    fdo::{
+        DBusProxy,
+        NameOwnerChanged,
+        NameOwnerChangedStream,
+    },
+    names::{
+        BusName,
+        OwnedUniqueName,
+    },
 };
 
 use crate::{
+    allowlist::Allowlist,
+    compat::Compat,
+    config::{
+        Config,
+        Hook,
+    },
+    flap::{
+        FlapDetection,
+        FlapHistory,
+    },
     launcher::Launcher,
     link::{
-        LinkDetails,
+        self,
+        Link,
+        LinkAction,
         LinkEvent,
+        LinkStates,
+        StateType,
     },
+    link_details::LinkDetails,
     network_dbus::NetworkManagerProxy,
     script::{
+        self,
         EnvVar,
         ScriptBuilder,
     },
+    script_index::ScriptIndex,
+    script_output::OutputMode,
+    settle::{
+        SettleTimes,
+        Settling,
+    },
+    state_file::StateFile,
 };
 
-/// A responder manages link event
+const NETWORKD_BUS_NAME: &str = "org.freedesktop.network1";
+
+/// Initial delay before reconnecting to System DBus, doubled on each failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay before reconnecting to System DBus
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Signal messages from System DBus
+enum BusEvent {
+    Link(zbus::Result<Message>),
+    NameOwnerChanged(NameOwnerChanged),
+}
+
+type BusEvents = stream::BoxStream<'static, BusEvent>;
+
+/// What wakes the broker up while listening
+enum Wake {
+    Bus(Option<BusEvent>),
+
+    /// End of the next settle time or cooldown
+    Deadline,
+
+    Reload,
+    ScriptsChanged,
+}
+
+/// Link which scripts respond to an event of
+#[derive(Debug, Clone, Copy)]
+struct EventLink<'a> {
+    /// Shared by every script which is run in response to the same incoming event, so scripts
+    /// of one event do not supersede each other
+    event_id: u64,
+
+    index: i32,
+    iface: &'a str,
+}
+
+/// Cached name and states of a link
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedLink {
+    name: String,
+    states: LinkStates,
+
+    /// Serial number of the latest `DescribeLink` reply of this link.
+    /// Signals with lower serial numbers are already reflected in cached states.
+    #[serde(skip)]
+    serial: u32,
+
+    /// Recent transitions for flap detection
+    #[serde(skip)]
+    history: FlapHistory,
+}
+
+/// Change of a link state
 #[derive(Debug)]
+struct StateChange {
+    state_type: StateType,
+    previous_state: Option<String>,
+    state: String,
+}
+
+impl CachedLink {
+    /// Update cached states, return states which are changed
+    fn update(&mut self, states: LinkStates) -> Vec<StateChange> {
+        let mut changes: Vec<StateChange> = Vec::new();
+        for (state_type, state) in states {
+            let previous_state = self.states.insert(state_type, state.clone());
+            if previous_state.as_ref() != Some(&state) {
+                changes.push(StateChange {
+                    state_type,
+                    previous_state,
+                    state,
+                });
+            }
+        }
+        changes
+    }
+
+    /// Change the name of a renamed link, its states are kept. Return the previous name if it
+    /// is changed.
+    fn rename(&mut self, name: &str) -> Option<String> {
+        if self.name == name {
+            return None;
+        }
+        Some(std::mem::replace(&mut self.name, name.to_string()))
+    }
+}
+
+/// Settings of a broker
+#[derive(Debug)]
+pub struct BrokerOptions {
+    /// Script root directories in priority order
+    pub script_root_dirs: Vec<PathBuf>,
+    pub script_timeout: u64,
+    pub script_grace: u64,
+    pub script_output: OutputMode,
+
+    /// Directory layout and environment variables of another dispatcher
+    pub compat: Option<Compat>,
+
+    /// How long a changed link state has to persist before it is responded to
+    pub settle: SettleTimes,
+
+    /// Suppress responses to a link which is flapping, none to disable
+    pub flap_detection: Option<FlapDetection>,
+
+    /// Users other than root whose scripts are run, as their owners
+    pub allowed_owners: Vec<u32>,
+
+    /// Configuration file, which is re-read on reload
+    pub config_file: PathBuf,
+
+    /// Hook rules of the configuration file, they run after the scripts of directories
+    pub hooks: Vec<Hook>,
+
+    /// Allowlist file, which is re-read on reload, none to run scripts without approval
+    pub allowlist_file: Option<PathBuf>,
+
+    /// Approved digests of scripts, it is handed over to the script index
+    pub allowlist: Option<Allowlist>,
+}
+
+/// A responder manages link event
 pub struct Broker {
-    script_root_dir: PathBuf,
-    script_timeout: u64,
+    options: BrokerOptions,
     launcher: Launcher,
     dbus_conn: Connection,
-    link_state_cache: BTreeMap<String, String>,
+    proxy: NetworkManagerProxy<'static>,
+
+    /// Signals which are subscribed before link state cache is initialized.
+    /// They are queued until the broker starts listening.
+    events: Option<BusEvents>,
+
+    /// Unique bus name of current systemd-networkd instance
+    networkd_owner: Option<OwnedUniqueName>,
+
+    /// Link name and states keyed by ifindex
+    link_state_cache: BTreeMap<i32, CachedLink>,
+
+    /// Link state cache is saved here, so it survives restarts of the broker
+    state_file: StateFile,
+
+    /// Changed link states which are waiting for their settle time
+    settling: Settling,
+
+    /// Validated scripts of the script root, kept up to date by inotify
+    scripts: ScriptIndex,
+
+    /// SIGHUP, which reloads configuration and scripts
+    reload_signals: Signals,
 }
 
 impl Broker {
-    pub async fn new(script_root_dir: PathBuf, script_timeout: u64) -> Result<Broker> {
-        debug!("Start script launcher");
-        let launcher = Launcher::new()?;
-
+    pub async fn new(mut options: BrokerOptions, launcher: Launcher) -> Result<Broker> {
         debug!("Connect to System DBus");
         let dbus_conn = Connection::system()
             .await
             .context("Could not connect to System DBus")?;
+        let proxy = NetworkManagerProxy::new(&dbus_conn)
+            .await
+            .context("Could not create proxy of org.freedesktop.network1.Manager")?;
+
+        // Subscribe before taking a snapshot of link states, so no link event is lost in between.
+        let events = Broker::subscribe(&dbus_conn).await?;
+        let networkd_owner = Broker::wait_for_networkd(&dbus_conn).await?;
 
         debug!("Initialize link state cache");
-        let link_state_cache = Broker::init_link_state_cache(&dbus_conn)
+        let link_state_cache = Broker::init_link_state_cache(&proxy)
             .await
             .context("Failed to create link state's cache")?;
 
+        let settling = Settling::new(options.settle.clone());
+        let scripts = ScriptIndex::new(
+            options.script_root_dirs.clone(),
+            None,
+            None,
+            options.allowed_owners.clone(),
+            options.allowlist.take(),
+        );
+        let reload_signals =
+            Signals::new([Signal::Hup]).context("Failed to handle SIGHUP for reloading")?;
         Ok(Broker {
-            script_root_dir,
-            script_timeout,
+            options,
             launcher,
             dbus_conn,
+            proxy,
+            events: Some(events),
+            networkd_owner: Some(networkd_owner),
             link_state_cache,
+            state_file: StateFile::in_runtime_dir(),
+            settling,
+            scripts,
+            reload_signals,
         })
     }
 
-    pub async fn listen(&mut self) -> Result<()> {
-        let rule: MatchRule = MatchRule::builder()
-            .msg_type(zbus::message::Type::Signal)
-            .interface("org.freedesktop.DBus.Properties")?
-            .member("PropertiesChanged")?
-            .path_namespace("/org/freedesktop/network1/link")?
-            .build();
+    /// Respond to link changes which happen while the broker is not running.
+    /// Link state cache which is saved by the previous run is compared with current links.
+    pub async fn replay(&mut self) -> Result<()> {
+        let Some(saved_cache) = self
+            .state_file
+            .load::<BTreeMap<i32, CachedLink>>()
+            .context("Failed to load saved link state cache")?
+        else {
+            debug!("No saved link state cache, nothing to replay");
+            return Ok(());
+        };
 
-        debug!("Create filtered message stream");
-        let mut stream: MessageStream = MessageStream::for_match_rule(rule, &self.dbus_conn, None)
-            .await
-            .context("Cannot create filtered message stream")?;
+        info!("Replay link changes since the last run");
+        self.link_state_cache = saved_cache;
+        self.resync().await
+    }
+
+    pub async fn listen(&mut self) -> Result<()> {
+        let mut events = match self.events.take() {
+            Some(events) => events,
+            None => Broker::subscribe(&self.dbus_conn).await?,
+        };
 
         debug!("Notify systemd that we are ready :)");
         if !daemon::notify(false, &[NotifyState::Ready])
@@ -103,110 +329,1008 @@ impl Broker {
 
         info!("{NOTIFY_MSG}");
 
-        futures_util::try_join!(async {
-            while let Some(msg) = stream.next().await {
-                let msg: Arc<Message> = match msg {
-                    Ok(m) => {
-                        debug!("New message: {m}");
-                        m.into()
+        self.save_link_state_cache();
+        loop {
+            // Wait for the next event, the end of the next settle time, a reload or a change of
+            // scripts
+            let deadline = self.next_deadline();
+            let wake = {
+                let bus = events.next().map(Wake::Bus);
+                let timer = pin!(async {
+                    match deadline {
+                        Some(deadline) => Timer::at(deadline).await,
+                        None => future::pending().await,
+                    };
+                    Wake::Deadline
+                });
+                let reload = self.reload_signals.next().map(|_| Wake::Reload);
+                let scripts = pin!(self.scripts.changed().map(|_| Wake::ScriptsChanged));
+                let first = future::select(bus, timer).map(|either| either.factor_first().0);
+                let second = future::select(reload, scripts).map(|either| either.factor_first().0);
+                future::select(first, second).await.factor_first().0
+            };
+
+            let event = match wake {
+                Wake::Bus(event) => event,
+                Wake::Deadline => {
+                    self.respond_to_settled().await;
+                    self.respond_to_stable().await;
+                    self.save_link_state_cache();
+                    continue;
+                }
+                Wake::Reload => {
+                    self.reload();
+                    continue;
+                }
+                Wake::ScriptsChanged => {
+                    info!("Scripts are changed, rescan them");
+                    self.scripts.rescan();
+                    continue;
+                }
+            };
+
+            match event {
+                Some(BusEvent::Link(msg)) => self.handle_message(msg).await,
+                Some(BusEvent::NameOwnerChanged(signal)) => {
+                    if let Err(err) = self.handle_name_owner_changed(&signal).await {
+                        warn!("{err:#}");
                     }
-                    Err(err) => {
-                        error!("{err:#}");
-                        continue;
+                }
+                None => {
+                    warn!("Lost connection to System DBus");
+                    events = self.reconnect().await;
+                }
+            }
+            self.save_link_state_cache();
+        }
+    }
+
+    /// Re-read the allowlist, the configuration file and scripts. An invalid allowlist or
+    /// configuration file is not applied, the current one is kept.
+    fn reload(&mut self) {
+        info!("Reload configuration and scripts");
+        if let Err(err) = daemon::notify(false, &[NotifyState::Reloading]) {
+            warn!("Cannot notify systemd, RELOADING=1: {err:#}");
+        }
+
+        if let Some(allowlist_file) = &self.options.allowlist_file {
+            match Allowlist::load(allowlist_file) {
+                Ok(allowlist) => self.scripts.set_allowlist(Some(allowlist)),
+                Err(err) => error!("{err:#}. Keep the current allowlist"),
+            }
+        }
+        match Config::load(&self.options.config_file, self.scripts.allowlist()) {
+            Ok(config) => {
+                info!(
+                    "Loaded {} hooks from `{}`",
+                    config.hooks.len(),
+                    self.options.config_file.display()
+                );
+                self.options.hooks = config.hooks;
+            }
+            Err(err) => error!("{err:#}. Keep the current hooks"),
+        }
+        self.scripts.rescan();
+
+        if let Err(err) = daemon::notify(false, &[NotifyState::Ready]) {
+            warn!("Cannot notify systemd, READY=1: {err:#}");
+        }
+    }
+
+    /// Save link state cache. States which are settling, or changed while a link is flapping,
+    /// are saved as the states which scripts are last run for, so they are replayed if the
+    /// broker is restarted.
+    fn save_link_state_cache(&mut self) {
+        let saved = if self.settling.is_empty()
+            && self
+                .link_state_cache
+                .values()
+                .all(|cached_link| !cached_link.history.is_flapping())
+        {
+            self.state_file.save(&self.link_state_cache)
+        } else {
+            let mut cache = self.link_state_cache.clone();
+            for (index, cached_link) in cache.iter_mut() {
+                cached_link.states = self.dispatched_states(*index);
+            }
+            self.state_file.save(&cache)
+        };
+        if let Err(err) = saved.context("Failed to save link state cache") {
+            warn!("{err:#}");
+        }
+    }
+
+    /// The states of a link which scripts are last run for
+    fn dispatched_states(&self, index: i32) -> LinkStates {
+        let Some(cached_link) = self.link_state_cache.get(&index) else {
+            return LinkStates::new();
+        };
+        if let Some(states) = cached_link.history.flapping_states() {
+            return states.clone();
+        }
+
+        let mut states = cached_link.states.clone();
+        for (state_type, previous_state) in self.settling.previous_states(index) {
+            match previous_state {
+                Some(previous_state) => {
+                    states.insert(state_type, previous_state.to_string());
+                }
+                None => {
+                    states.remove(&state_type);
+                }
+            }
+        }
+        states
+    }
+
+    /// Subscribe to link events and ownership changes of systemd-networkd's bus name
+    async fn subscribe(conn: &Connection) -> Result<BusEvents> {
+        let rule: MatchRule = MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .interface("org.freedesktop.DBus.Properties")?
+            .member("PropertiesChanged")?
+            .path_namespace("/org/freedesktop/network1/link")?
+            .build();
+
+        debug!("Create filtered message stream");
+        let link_stream: MessageStream = MessageStream::for_match_rule(rule, conn, None)
+            .await
+            .context("Cannot create filtered message stream")?;
+
+        debug!("Watch owner of {NETWORKD_BUS_NAME}");
+        let name_owner_stream: NameOwnerChangedStream = DBusProxy::new(conn)
+            .await?
+            .receive_name_owner_changed_with_args(&[(0, NETWORKD_BUS_NAME)])
+            .await
+            .with_context(|| format!("Cannot watch owner of {NETWORKD_BUS_NAME}"))?;
+
+        Ok(stream::select(
+            link_stream.map(BusEvent::Link),
+            name_owner_stream.map(BusEvent::NameOwnerChanged),
+        )
+        .boxed())
+    }
+
+    /// Reconnect to System DBus with backoff, then synchronize links which may be changed while
+    /// disconnected.
+    async fn reconnect(&mut self) -> BusEvents {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            info!("Reconnect to System DBus in {} seconds", delay.as_secs());
+            Timer::after(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+
+            match self
+                .connect()
+                .await
+                .context("Failed to reconnect to System DBus")
+            {
+                Ok(events) => return events,
+                Err(err) => warn!("{err:#}"),
+            }
+        }
+    }
+
+    async fn connect(&mut self) -> Result<BusEvents> {
+        self.dbus_conn = Connection::system()
+            .await
+            .context("Could not connect to System DBus")?;
+        self.proxy = NetworkManagerProxy::new(&self.dbus_conn)
+            .await
+            .context("Could not create proxy of org.freedesktop.network1.Manager")?;
+
+        let events = Broker::subscribe(&self.dbus_conn).await?;
+        self.networkd_owner = Some(Broker::wait_for_networkd(&self.dbus_conn).await?);
+        self.resync().await?;
+        info!("Reconnected to System DBus");
+        Ok(events)
+    }
+
+    /// Wait until systemd-networkd owns its bus name, return its unique bus name
+    async fn wait_for_networkd(conn: &Connection) -> Result<OwnedUniqueName> {
+        let dbus_proxy = DBusProxy::new(conn).await?;
+        let mut name_owner_stream = dbus_proxy
+            .receive_name_owner_changed_with_args(&[(0, NETWORKD_BUS_NAME)])
+            .await
+            .with_context(|| format!("Cannot watch owner of {NETWORKD_BUS_NAME}"))?;
+
+        let bus_name = BusName::try_from(NETWORKD_BUS_NAME)?;
+        if dbus_proxy.name_has_owner(bus_name.clone()).await? {
+            return Ok(dbus_proxy.get_name_owner(bus_name).await?);
+        }
+
+        info!("Wait for {NETWORKD_BUS_NAME}...");
+        while let Some(signal) = name_owner_stream.next().await {
+            if let Some(owner) = signal.args()?.new_owner().as_ref() {
+                info!("{NETWORKD_BUS_NAME} is available");
+                return Ok(owner.to_owned().into());
+            }
+        }
+
+        bail!("Lost connection to System DBus while waiting for {NETWORKD_BUS_NAME}");
+    }
+
+    async fn handle_message(&mut self, msg: zbus::Result<Message>) {
+        let msg: Arc<Message> = match msg {
+            Ok(m) => {
+                debug!("New message: {m}");
+                m.into()
+            }
+            Err(err) => {
+                error!("{err:#}");
+                return;
+            }
+        };
+
+        if msg.header().sender() != self.networkd_owner.as_deref() {
+            debug!("Skip event, it is not sent by current instance of {NETWORKD_BUS_NAME}");
+            return;
+        }
+
+        match LinkEvent::new(&msg) {
+            Ok(link_event) => {
+                debug!("Link Event: {link_event}");
+                if let Err(err) = self.handle_link_event(&link_event).await {
+                    warn!("{err:#}");
+                }
+            }
+            Err(err) => debug!("{err:#}"),
+        }
+    }
+
+    /// Link states may be changed while systemd-networkd is restarted without any link event,
+    /// synchronize them with new instance of systemd-networkd.
+    async fn handle_name_owner_changed(&mut self, signal: &NameOwnerChanged) -> Result<()> {
+        let Some(owner) = signal
+            .args()?
+            .new_owner()
+            .as_ref()
+            .map(|owner| owner.to_owned())
+        else {
+            warn!("{NETWORKD_BUS_NAME} is gone, wait for it to come back");
+            self.networkd_owner = None;
+            return Ok(());
+        };
+        self.networkd_owner = Some(owner.into());
+
+        info!("{NETWORKD_BUS_NAME} has a new owner, synchronize link states");
+        self.resync()
+            .await
+            .context("Failed to synchronize link states")
+    }
+
+    /// Respond to current state of each link.
+    /// The states are taken from link state cache, so the link events which are queued since
+    /// the cache is initialized are still compared with them.
+    pub async fn trigger_all(&self) -> Result<()> {
+        let event_id = script::next_event_id();
+        for (index, link) in &self.link_state_cache {
+            info!("run startup-triggers on '{}'", link.name);
+
+            let changes: Vec<StateChange> = link
+                .states
+                .iter()
+                .map(|(state_type, state)| StateChange {
+                    state_type: *state_type,
+                    previous_state: None,
+                    state: state.clone(),
+                })
+                .collect();
+
+            if let Err(err) = self
+                .respond(
+                    EventLink {
+                        event_id,
+                        index: *index,
+                        iface: &link.name,
+                    },
+                    &changes,
+                    None,
+                )
+                .await
+                .with_context(|| format!("Failed to respond to `{}`", link.name))
+            {
+                warn!("{err:#}");
+            }
+        }
+
+        info!("Finished 'run startup-triggers'");
+        Ok(())
+    }
+
+    /// Compare changed link states of an event with link state cache,
+    /// then respond to the changes.
+    async fn handle_link_event(&mut self, event: &LinkEvent) -> Result<()> {
+        let event_id = script::next_event_id();
+        let Some(cached_link) = self.link_state_cache.get(&event.index) else {
+            // A new link is described and responded to during synchronization.
+            // Its states are newer than the ones in this event.
+            debug!("Unknown link {}, synchronize links", event.index);
+            return self
+                .sync_links(event_id)
+                .await
+                .context("Failed to synchronize links");
+        };
+        if event.serial < cached_link.serial {
+            debug!("Skip event, it is older than link state cache");
+            return Ok(());
+        }
+
+        // Renaming keeps the ifindex of a link, and it is not signaled by systemd-networkd
+        match link::kernel_name(event.index) {
+            Ok(Some(name)) => self.rename_link(event_id, event.index, &name),
+            Ok(None) => debug!("Link {} is gone from the kernel", event.index),
+            Err(err) => warn!("{err:#}"),
+        }
+        let iface = self.link_state_cache[&event.index].name.clone();
+
+        let (states, json, serial) = if event.invalidated {
+            debug!("Link states of {iface} are invalidated");
+            let link = Link::describe(&self.proxy, event.index, &iface).await?;
+            (
+                link.link_details.states(),
+                Some(link.link_details_json),
+                link.serial,
+            )
+        } else {
+            (event.states.clone(), None, event.serial)
+        };
+
+        // The states which scripts are last run for, in case the link starts flapping
+        let dispatched_states = self.dispatched_states(event.index);
+        let changes = match self.link_state_cache.get_mut(&event.index) {
+            Some(cached_link) => {
+                debug!("Update link state cache of {iface}");
+                cached_link.serial = serial;
+                cached_link.update(states)
+            }
+            None => Vec::new(),
+        };
+
+        let now = Instant::now();
+        let flapping = !changes.is_empty()
+            && self.detect_flapping(event_id, event.index, &iface, dispatched_states, now);
+
+        if flapping {
+            // Responded to when the link is stable
+            debug!("Skip event, link {iface} is flapping");
+        } else {
+            // Changes with settle time are responded to when it ends
+            let changes: Vec<StateChange> = changes
+                .into_iter()
+                .filter(|change| {
+                    let settling = self.settling.settle(
+                        event.index,
+                        change.state_type,
+                        change.previous_state.clone(),
+                        &change.state,
+                        now,
+                    );
+                    if settling {
+                        debug!(
+                            "Wait for '{}' {} of '{iface}' to settle",
+                            change.state, change.state_type
+                        );
                     }
-                };
+                    !settling
+                })
+                .collect();
 
-                match LinkEvent::new(&msg, &self.dbus_conn).await {
-                    Ok(link_event) => {
-                        debug!("Link Event: {link_event}");
-
-                        match self.link_state_cache.get_mut(&link_event.iface) {
-                            Some(previous_operational_state) => {
-                                if *previous_operational_state == link_event.state {
-                                    debug!("Skip event, no change in OperationalState");
-                                    continue;
-                                }
-
-                                debug!("Update link state cache of {}", link_event.iface);
-                                previous_operational_state.clone_from(&link_event.state);
-                            }
-                            None => {
-                                debug!("Insert new link state cache");
-                                self.link_state_cache
-                                    .insert(link_event.iface.clone(), link_event.state.clone());
-                            }
-                        }
+            if changes.is_empty() {
+                debug!("Skip event, no change in link states to respond to now");
+            } else {
+                self.respond(
+                    EventLink {
+                        event_id,
+                        index: event.index,
+                        iface: &iface,
+                    },
+                    &changes,
+                    json,
+                )
+                .await?;
+            }
+        }
+
+        // networkd lingers a link before dropping it
+        if event
+            .states
+            .get(&StateType::Administrative)
+            .is_some_and(|state| state == "linger")
+        {
+            debug!("Link {iface} is lingering, synchronize links");
+            self.sync_links(event_id)
+                .await
+                .context("Failed to synchronize links")?;
+        }
+
+        Ok(())
+    }
+
+    /// Record a transition of a link, and respond when it starts flapping.
+    /// Return `true` if the link is flapping.
+    fn detect_flapping(
+        &mut self,
+        event_id: u64,
+        index: i32,
+        iface: &str,
+        dispatched_states: LinkStates,
+        now: Instant,
+    ) -> bool {
+        let Some(detection) = &self.options.flap_detection else {
+            return false;
+        };
+        let Some(cached_link) = self.link_state_cache.get_mut(&index) else {
+            return false;
+        };
 
-                        if let Err(err) = self.respond(&link_event) {
-                            warn!("{err:#}");
+        let started = cached_link
+            .history
+            .record(detection, now, dispatched_states);
+        let flapping = cached_link.history.is_flapping();
+        if let Some(count) = started {
+            warn!(
+                "Link {iface} is flapping, {count} transitions within {} seconds",
+                detection.window().as_secs()
+            );
+            // Settling states are responded to when the link is stable
+            self.settling.remove_link(index);
+            if let Err(err) = self.respond_to_action(
+                LinkAction::Flapping,
+                EventLink {
+                    event_id,
+                    index,
+                    iface,
+                },
+                None,
+                None,
+                vec![EnvVar::FlapCount(count.to_string())],
+            ) {
+                warn!("{err:#}");
+            }
+        }
+
+        flapping
+    }
+
+    /// Respond to flapping links which become stable, with the net changes of their states
+    /// since they start flapping
+    async fn respond_to_stable(&mut self) {
+        let Some(detection) = self.options.flap_detection else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut stable_links = Vec::new();
+        for (index, cached_link) in self.link_state_cache.iter_mut() {
+            if let Some((previous_states, count)) = cached_link.history.take_stable(&detection, now)
+            {
+                stable_links.push((*index, cached_link.name.clone(), previous_states, count));
+            }
+        }
+
+        // `stable.d` and the net changes of a link are one event
+        let event_id = script::next_event_id();
+        for (index, iface, previous_states, count) in stable_links {
+            info!("Link {iface} is stable after {count} transitions");
+            let link = EventLink {
+                event_id,
+                index,
+                iface: &iface,
+            };
+            if let Err(err) = self.respond_to_action(
+                LinkAction::Stable,
+                link,
+                None,
+                None,
+                vec![EnvVar::FlapCount(count.to_string())],
+            ) {
+                warn!("{err:#}");
+            }
+
+            let Some(cached_link) = self.link_state_cache.get(&index) else {
+                continue;
+            };
+            let changes: Vec<StateChange> = cached_link
+                .states
+                .iter()
+                .filter(|(state_type, state)| previous_states.get(state_type) != Some(*state))
+                .map(|(state_type, state)| StateChange {
+                    state_type: *state_type,
+                    previous_state: previous_states.get(state_type).cloned(),
+                    state: state.clone(),
+                })
+                .collect();
+            if changes.is_empty() {
+                continue;
+            }
+            if let Err(err) = self
+                .respond(link, &changes, None)
+                .await
+                .with_context(|| format!("Failed to respond to `{iface}`"))
+            {
+                warn!("{err:#}");
+            }
+        }
+    }
+
+    /// When the next settle time or flap cooldown ends
+    fn next_deadline(&self) -> Option<Instant> {
+        let stable_deadline = self.options.flap_detection.and_then(|detection| {
+            self.link_state_cache
+                .values()
+                .filter_map(|cached_link| cached_link.history.stable_deadline(&detection))
+                .min()
+        });
+        match (self.settling.next_deadline(), stable_deadline) {
+            (Some(settle), Some(stable)) => Some(settle.min(stable)),
+            (settle, stable) => settle.or(stable),
+        }
+    }
+
+    /// Respond to link states whose settle time has ended, if they are not back to the states
+    /// which scripts are last run for
+    async fn respond_to_settled(&mut self) {
+        let mut responses: BTreeMap<i32, Vec<StateChange>> = BTreeMap::new();
+        for (index, state_type, previous_state) in self.settling.take_settled(Instant::now()) {
+            let Some(cached_link) = self.link_state_cache.get(&index) else {
+                continue;
+            };
+            let Some(state) = cached_link.states.get(&state_type) else {
+                continue;
+            };
+            if previous_state.as_ref() == Some(state) {
+                debug!(
+                    "Skip settled {state_type} of '{}', it is back to '{state}'",
+                    cached_link.name
+                );
+                continue;
+            }
+            responses.entry(index).or_default().push(StateChange {
+                state_type,
+                previous_state,
+                state: state.clone(),
+            });
+        }
+
+        let event_id = script::next_event_id();
+        for (index, changes) in responses {
+            let Some(cached_link) = self.link_state_cache.get(&index) else {
+                continue;
+            };
+            if let Err(err) = self
+                .respond(
+                    EventLink {
+                        event_id,
+                        index,
+                        iface: &cached_link.name,
+                    },
+                    &changes,
+                    None,
+                )
+                .await
+                .with_context(|| format!("Failed to respond to `{}`", cached_link.name))
+            {
+                warn!("{err:#}");
+            }
+        }
+    }
+
+    /// Compare current links and their states with link state cache.
+    /// Respond to any difference.
+    async fn resync(&mut self) -> Result<()> {
+        // Serial numbers start over with a new instance of systemd-networkd, or a new bus
+        // connection. Signals of a link which fails to be described below must not be skipped
+        // as older than its cached serial number.
+        for cached_link in self.link_state_cache.values_mut() {
+            cached_link.serial = 0;
+        }
+
+        let event_id = script::next_event_id();
+        self.sync_links(event_id).await?;
+
+        let indexes: Vec<i32> = self.link_state_cache.keys().copied().collect();
+        for index in indexes {
+            let Some(cached_link) = self.link_state_cache.get_mut(&index) else {
+                continue;
+            };
+            let iface = cached_link.name.clone();
+
+            let link = match Link::describe(&self.proxy, index, &iface).await {
+                Ok(link) => link,
+                Err(err) => {
+                    debug!("{err:#}");
+                    continue;
+                }
+            };
+
+            cached_link.serial = link.serial;
+            let mut changes = cached_link.update(link.link_details.states());
+            if cached_link.history.is_flapping() {
+                // Responded to when the link is stable
+                continue;
+            }
+
+            // A settling state is responded to right away, as a change from the state which
+            // scripts are last run for
+            for state_type in StateType::ALL {
+                let Some(previous_state) = self.settling.cancel(index, state_type) else {
+                    continue;
+                };
+                match changes
+                    .iter_mut()
+                    .find(|change| change.state_type == state_type)
+                {
+                    Some(change) => change.previous_state = previous_state,
+                    None => {
+                        if let Some(state) = cached_link.states.get(&state_type) {
+                            changes.push(StateChange {
+                                state_type,
+                                previous_state,
+                                state: state.clone(),
+                            });
                         }
                     }
-                    Err(err) => debug!("{err:#}"),
                 }
             }
-            Ok::<(), zbus::Error>(())
-        },)?;
+            changes.retain(|change| change.previous_state.as_ref() != Some(&change.state));
+            if changes.is_empty() {
+                continue;
+            }
+
+            if let Err(err) = self
+                .respond(
+                    EventLink {
+                        event_id,
+                        index,
+                        iface: &iface,
+                    },
+                    &changes,
+                    Some(link.link_details_json),
+                )
+                .await
+            {
+                warn!("{err:#}");
+            }
+        }
 
         Ok(())
     }
 
-    pub async fn trigger_all(&self) -> Result<()> {
-        let proxy = NetworkManagerProxy::new(&self.dbus_conn).await?;
-        let links = proxy.list_links().await?;
-        for (index, name, path) in links {
-            info!("run startup-triggers on '{name}'");
+    /// Compare current links with link state cache.
+    /// Respond to links which have been added, removed or renamed since the last synchronization.
+    ///
+    /// * `event_id` - Event which the synchronization is run for, it is shared by the scripts of
+    ///   a new link, e.g. `added.d` and the scripts of its initial states
+    ///
+    async fn sync_links(&mut self, event_id: u64) -> Result<()> {
+        let links = self.proxy.list_links().await?;
 
-            let describe_link = proxy.describe_link(index).await?;
+        let removed_links: Vec<i32> = self
+            .link_state_cache
+            .keys()
+            .filter(|cached_index| !links.iter().any(|(index, _, _)| index == *cached_index))
+            .copied()
+            .collect();
+        for index in removed_links {
+            let Some(link) = self.link_state_cache.remove(&index) else {
+                continue;
+            };
+            debug!("Evict link state cache of {}", link.name);
+            self.settling.remove_link(index);
+            if let Err(err) = self.respond_to_action(
+                LinkAction::Removed,
+                EventLink {
+                    event_id,
+                    index,
+                    iface: &link.name,
+                },
+                None,
+                None,
+                Vec::new(),
+            ) {
+                warn!("{err:#}");
+            }
+        }
 
-            let link_details = match serde_json::from_str::<LinkDetails>(&describe_link)
-                .with_context(|| format!("Cannot get link state of `{name}`"))
-            {
-                Ok(link_details) => link_details,
-                Err(err) => bail!("{err:#}"),
+        for (index, name, _path) in &links {
+            if self.link_state_cache.contains_key(index) {
+                self.rename_link(event_id, *index, name);
+                continue;
+            }
+
+            let link = match Link::describe(&self.proxy, *index, name).await {
+                Ok(link) => link,
+                Err(err) => {
+                    // The link might be already gone, it will be evicted on next synchronization.
+                    debug!("{err:#}");
+                    continue;
+                }
             };
 
-            let event = Box::new(LinkEvent {
+            debug!("Insert new link state cache");
+            let states = link.link_details.states();
+            self.link_state_cache.insert(
+                *index,
+                CachedLink {
+                    name: name.clone(),
+                    states: states.clone(),
+                    serial: link.serial,
+                    history: FlapHistory::default(),
+                },
+            );
+
+            let event_link = EventLink {
+                event_id,
+                index: *index,
                 iface: name,
-                state: link_details.operational_state.clone(),
-                path: path.to_string(),
-                link_details,
-                link_details_json: describe_link,
-            });
+            };
+            if let Err(err) = self.respond_to_action(
+                LinkAction::Added,
+                event_link,
+                None,
+                Some(&link.link_details_json),
+                Vec::new(),
+            ) {
+                warn!("{err:#}");
+            }
 
+            let changes: Vec<StateChange> = states
+                .into_iter()
+                .map(|(state_type, state)| StateChange {
+                    state_type,
+                    previous_state: None,
+                    state,
+                })
+                .collect();
             if let Err(err) = self
-                .respond(&event)
-                .with_context(|| format!("Failed to respond to `{event}`"))
+                .respond(event_link, &changes, Some(link.link_details_json))
+                .await
             {
                 warn!("{err:#}");
             }
         }
 
-        info!("Finished 'run startup-triggers'");
         Ok(())
     }
 
-    fn respond(&self, event: &LinkEvent) -> Result<()> {
-        info!("Respond to '{}' event of '{}'", &event.state, &event.iface);
+    /// Update the name of a cached link, and respond if it is renamed. Link states are kept as
+    /// is, only link name is changed.
+    fn rename_link(&mut self, event_id: u64, index: i32, name: &str) {
+        let Some(previous_name) = self
+            .link_state_cache
+            .get_mut(&index)
+            .and_then(|cached_link| cached_link.rename(name))
+        else {
+            return;
+        };
+        if let Err(err) = self.respond_to_action(
+            LinkAction::Renamed,
+            EventLink {
+                event_id,
+                index,
+                iface: name,
+            },
+            Some(&previous_name),
+            None,
+            Vec::new(),
+        ) {
+            warn!("{err:#}");
+        }
+    }
 
-        // Get all scripts associated with current event
-        let state_dir = format!("{}.d", event.state);
-        let script_path = self.script_root_dir.join(state_dir);
-        let scripts = match ScriptBuilder::build_from(&script_path, None, None)
-            .with_context(|| format!("Could not get scripts from `{}`", script_path.display()))
-        {
-            Ok(s) => s,
-            Err(err) => bail!("{err:#}"),
+    /// Respond to appearance, disappearance or renaming of a link
+    ///
+    /// * `previous_iface` - Link name before renaming
+    ///
+    fn respond_to_action(
+        &self,
+        action: LinkAction,
+        link: EventLink<'_>,
+        previous_iface: Option<&str>,
+        json: Option<&str>,
+        env_vars: Vec<EnvVar>,
+    ) -> Result<()> {
+        let iface = link.iface;
+        match previous_iface {
+            Some(previous_iface) => {
+                info!("Respond to '{action}' event of '{previous_iface}' to '{iface}'")
+            }
+            None => info!("Respond to '{action}' event of '{iface}'"),
+        }
+
+        let mut scripts = self.find_scripts(&[PathBuf::from(format!("{action}.d"))]);
+        let action_name = action.to_string();
+        let hooks = self.matching_hooks(None, &action_name, None, iface);
+        if !hooks.is_empty() {
+            let link_details = json.and_then(|json| {
+                serde_json::from_str::<LinkDetails>(json)
+                    .inspect_err(|err| warn!("Cannot parse link details of `{iface}`: {err}"))
+                    .ok()
+            });
+            scripts.extend(
+                hooks
+                    .into_iter()
+                    .filter(|hook| hook.matches_link(link_details.as_ref()))
+                    .map(Hook::builder),
+            );
+        }
+        self.queue_scripts(scripts, |script| {
+            let mut script = script
+                .set_event_id(link.event_id)
+                .set_ifindex(link.index)
+                .set_arg0(&action.to_string())
+                .set_arg1(iface)
+                .add_env(EnvVar::DeviceIface(iface.to_string()))
+                .add_env(EnvVar::BrokerAction(action.to_string()));
+            if let Some(previous_iface) = previous_iface {
+                script = script
+                    .set_arg2(previous_iface)
+                    .add_env(EnvVar::PreviousIface(previous_iface.to_string()));
+            }
+            if let Some(json) = json {
+                script = script.add_env(EnvVar::Json(json.to_string()));
+            }
+            env_vars
+                .iter()
+                .cloned()
+                .fold(script, |script, env_var| script.add_env(env_var))
+        })
+    }
+
+    /// Respond to state changes of a link
+    ///
+    /// * `json` - Link details in JSON format. If it is not provided, it is described only when
+    ///   there is any script to run.
+    ///
+    async fn respond(
+        &self,
+        link: EventLink<'_>,
+        changes: &[StateChange],
+        json: Option<String>,
+    ) -> Result<()> {
+        let EventLink {
+            event_id,
+            index,
+            iface,
+        } = link;
+        let mut responses = Vec::new();
+        for change in changes {
+            info!(
+                "Respond to '{}' {} event of '{iface}'",
+                change.state, change.state_type
+            );
+
+            // Get all scripts associated with current event
+            let script_paths = match self.options.compat {
+                Some(compat) => compat
+                    .script_paths(change.state_type, &change.state)
+                    .unwrap_or_default(),
+                None => self.script_paths(change),
+            };
+            let scripts = self.find_scripts(&script_paths);
+            let hooks = self.matching_hooks(
+                Some(change.state_type),
+                &change.state,
+                change.previous_state.as_deref(),
+                iface,
+            );
+            if !scripts.is_empty() || !hooks.is_empty() {
+                responses.push((change, scripts, hooks));
+            }
+        }
+
+        if responses.is_empty() {
+            return Ok(());
+        }
+
+        let json = match json {
+            Some(json) => json,
+            None => self
+                .proxy
+                .describe_link(index)
+                .await
+                .with_context(|| format!("Cannot get link details of `{iface}`"))?,
         };
 
-        // Push scripts with args + envs to launcher's queue.
+        let link_details = serde_json::from_str::<LinkDetails>(&json)
+            .with_context(|| format!("Cannot parse link details of `{iface}`"))?;
+        let link_env_vars = EnvVar::from_link_details(index, &link_details);
+
+        for (change, mut scripts, hooks) in responses {
+            scripts.extend(
+                hooks
+                    .into_iter()
+                    .filter(|hook| hook.matches_link(Some(&link_details)))
+                    .map(Hook::builder),
+            );
+            let previous_state = change.previous_state.as_deref().unwrap_or_default();
+            self.queue_scripts(scripts, |script| {
+                let script = link_env_vars
+                    .iter()
+                    .cloned()
+                    .fold(script, |script, env_var| script.add_env(env_var))
+                    .set_event_id(event_id)
+                    .set_ifindex(index)
+                    .set_arg0(&change.state)
+                    .set_arg1(iface)
+                    .set_arg2(previous_state)
+                    .add_env(EnvVar::DeviceIface(iface.to_string()))
+                    .add_env(EnvVar::BrokerAction(change.state.clone()))
+                    .add_env(EnvVar::PreviousState(previous_state.to_string()))
+                    .add_env(EnvVar::StateType(change.state_type.to_string()))
+                    .add_env(EnvVar::Json(json.clone()));
+                match self.options.compat {
+                    Some(compat) => compat
+                        .env_vars(iface, &change.state, &link_details, &json)
+                        .into_iter()
+                        .fold(script, |script, env_var| script.add_env(env_var)),
+                    None => script,
+                }
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Directories of scripts which respond to a state change, relative to the script root
+    /// directories, in order
+    fn script_paths(&self, change: &StateChange) -> Vec<PathBuf> {
+        let state_type_root = match change.state_type.dir() {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::new(),
+        };
+        let mut script_paths = vec![state_type_root.join(format!("{}.d", change.state))];
+        if let Some(previous_state) = &change.previous_state {
+            debug!(
+                "Transition of {} from '{previous_state}' to '{}'",
+                change.state_type, change.state
+            );
+            let transitions_dir = state_type_root.join("transitions");
+            script_paths
+                .push(transitions_dir.join(format!("{previous_state}-to-{}.d", change.state)));
+            script_paths.push(transitions_dir.join(format!("*-to-{}.d", change.state)));
+        }
+        script_paths
+    }
+
+    /// Hook rules which match an event, before their conditions on link details are checked
+    fn matching_hooks(
+        &self,
+        state_type: Option<StateType>,
+        state: &str,
+        previous_state: Option<&str>,
+        iface: &str,
+    ) -> Vec<&Hook> {
+        self.options
+            .hooks
+            .iter()
+            .filter(|hook| hook.matches_event(state_type, state, previous_state, iface))
+            .inspect(|hook| debug!("Hook `{}` matches '{state}' event of '{iface}'", hook.name))
+            .collect()
+    }
+
+    /// Get scripts of all script paths from the script index, in order
+    fn find_scripts(&self, script_paths: &[PathBuf]) -> Vec<ScriptBuilder> {
+        script_paths
+            .iter()
+            .flat_map(|script_path| self.scripts.scripts(script_path))
+            .collect()
+    }
+
+    /// Push scripts to launcher's queue
+    ///
+    /// * `setup` - Set args + envs of each script
+    ///
+    fn queue_scripts<F>(&self, scripts: Vec<ScriptBuilder>, setup: F) -> Result<()>
+    where
+        F: Fn(ScriptBuilder) -> ScriptBuilder,
+    {
         for script in scripts {
-            let script = script
-                .set_arg0(&event.state.clone())
-                .set_arg1(&event.iface.clone())
-                .add_env(EnvVar::DeviceIface(event.iface.clone()))
-                .add_env(EnvVar::BrokerAction(event.state.clone()))
-                .add_env(EnvVar::Json(event.link_details_json.clone()))
-                .set_default_timeout(self.script_timeout)
+            let script = setup(script)
+                .set_default_timeout(self.options.script_timeout)
+                .set_default_grace(self.options.script_grace)
+                .set_output_mode(self.options.script_output)
                 .build();
             debug!("Add script {script:?} to launcher's queue");
             if let Err(err) = self.launcher.add(script) {
@@ -217,21 +1341,22 @@ impl Broker {
         Ok(())
     }
 
-    async fn init_link_state_cache(conn: &Connection) -> Result<BTreeMap<String, String>> {
-        let proxy = NetworkManagerProxy::new(conn).await?;
+    async fn init_link_state_cache(
+        proxy: &NetworkManagerProxy<'_>,
+    ) -> Result<BTreeMap<i32, CachedLink>> {
         let links = proxy.list_links().await?;
-        let mut cache: BTreeMap<String, String> = BTreeMap::new();
+        let mut cache: BTreeMap<i32, CachedLink> = BTreeMap::new();
         for (index, name, _path) in links {
-            let describe_link = proxy.describe_link(index).await?;
-
-            let link_details = match serde_json::from_str::<LinkDetails>(&describe_link)
-                .with_context(|| format!("Cannot get link state of `{name}`"))
-            {
-                Ok(link_details) => link_details,
-                Err(err) => bail!("{err:#}"),
-            };
-
-            cache.insert(name, link_details.operational_state);
+            let link = Link::describe(proxy, index, &name).await?;
+            cache.insert(
+                index,
+                CachedLink {
+                    name,
+                    states: link.link_details.states(),
+                    serial: link.serial,
+                    history: FlapHistory::default(),
+                },
+            );
         }
         Ok(cache)
     }
@@ -243,12 +1368,99 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_update_cached_link() {
+        let mut cached_link = CachedLink {
+            name: "wlp3s0".to_string(),
+            states: LinkStates::from([
+                (StateType::Operational, "degraded".to_string()),
+                (StateType::Carrier, "carrier".to_string()),
+            ]),
+            serial: 1,
+            history: FlapHistory::default(),
+        };
+
+        let changes = cached_link.update(LinkStates::from([
+            (StateType::Operational, "routable".to_string()),
+            (StateType::Carrier, "carrier".to_string()),
+            (StateType::Ipv6Address, "routable".to_string()),
+        ]));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].state_type, StateType::Operational);
+        assert_eq!(changes[0].previous_state.as_deref(), Some("degraded"));
+        assert_eq!(changes[0].state, "routable");
+        assert_eq!(changes[1].state_type, StateType::Ipv6Address);
+        assert_eq!(changes[1].previous_state, None);
+        assert_eq!(changes[1].state, "routable");
+
+        let changes = cached_link.update(LinkStates::from([(
+            StateType::Operational,
+            "routable".to_string(),
+        )]));
+        assert!(changes.is_empty());
+        assert_eq!(cached_link.states.len(), 3);
+    }
+
+    #[test]
+    fn test_rename_cached_link() {
+        let mut cached_link = CachedLink {
+            name: "eth0".to_string(),
+            states: LinkStates::from([(StateType::Operational, "degraded".to_string())]),
+            serial: 1,
+            history: FlapHistory::default(),
+        };
+        assert_eq!(cached_link.rename("eth0"), None);
+        assert_eq!(cached_link.rename("enp3s0").as_deref(), Some("eth0"));
+
+        // States are carried over, the next change is of the new name
+        let changes = cached_link.update(LinkStates::from([(
+            StateType::Operational,
+            "routable".to_string(),
+        )]));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].previous_state.as_deref(), Some("degraded"));
+        assert_eq!(changes[0].state, "routable");
+
+        let json = serde_json::to_string(&BTreeMap::from([(3, cached_link)])).unwrap();
+        assert_eq!(
+            json,
+            r#"{"3":{"name":"enp3s0","states":{"OperationalState":"routable"}}}"#
+        );
+    }
+
+    #[test]
+    fn test_serialize_link_state_cache() {
+        let cache = BTreeMap::from([(
+            3,
+            CachedLink {
+                name: "wlp3s0".to_string(),
+                states: LinkStates::from([
+                    (StateType::Operational, "routable".to_string()),
+                    (StateType::Ipv6Address, "degraded".to_string()),
+                ]),
+                serial: 10,
+                history: FlapHistory::default(),
+            },
+        )]);
+
+        let json = serde_json::to_string(&cache).unwrap();
+        assert_eq!(
+            json,
+            r#"{"3":{"name":"wlp3s0","states":{"OperationalState":"routable","IPv6AddressState":"degraded"}}}"#
+        );
+
+        let saved_cache: BTreeMap<i32, CachedLink> = serde_json::from_str(&json).unwrap();
+        assert_eq!(saved_cache[&3].name, "wlp3s0");
+        assert_eq!(saved_cache[&3].states, cache[&3].states);
+        assert_eq!(saved_cache[&3].serial, 0);
+    }
+
     #[test]
     #[ignore]
     fn test_init_link_state_cache() {
         // Get all network links using NetworkctlCtl command
         let stdout = cmd!("networkctl", "--no-pager", "--no-legend", "list")
-            .pipe(cmd!("awk", "{ print $2, $4 }"))
+            .pipe(cmd!("awk", "{ print $1, $2, $4 }"))
             .read()
             .unwrap();
         let links: Vec<Vec<&str>> = stdout
@@ -258,9 +1470,15 @@ mod tests {
 
         zbus::block_on(async {
             let dbus_conn = Connection::system().await.unwrap();
-            let cache = Broker::init_link_state_cache(&dbus_conn).await.unwrap();
+            let proxy = NetworkManagerProxy::new(&dbus_conn).await.unwrap();
+            let cache = Broker::init_link_state_cache(&proxy).await.unwrap();
             for link in links {
-                assert_eq!(cache.get(link[0]), Some(&link[1].to_string()));
+                let cached_link = cache.get(&link[0].parse::<i32>().unwrap()).unwrap();
+                assert_eq!(cached_link.name, link[1]);
+                assert_eq!(
+                    cached_link.states.get(&StateType::Operational),
+                    Some(&link[2].to_string())
+                );
             }
         });
     }
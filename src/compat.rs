@@ -0,0 +1,158 @@
+//! # Compatibility with other network event dispatchers
+//!
+//! Scripts which are written for another dispatcher expect its directory layout and its
+//! environment variables, so they can run unmodified.
+
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+
+use crate::{
+    link::StateType,
+    link_details::LinkDetails,
+    script::EnvVar,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compat {
+    /// Hooks of networkd-dispatcher, `<STATE>.d` of `OperationalState` and `AdministrativeState`
+    /// directly under the script root directory
+    NetworkdDispatcher,
+}
+
+impl Compat {
+    /// Default locations under which to look for scripts, in priority order
+    pub fn default_script_dirs(&self) -> &'static [&'static str] {
+        match self {
+            Compat::NetworkdDispatcher => {
+                &["/usr/lib/networkd-dispatcher", "/etc/networkd-dispatcher"]
+            }
+        }
+    }
+
+    /// Directories of scripts which respond to a state, relative to the script root
+    /// directories. `None` if this state type is not supported.
+    pub fn script_paths(&self, state_type: StateType, state: &str) -> Option<Vec<PathBuf>> {
+        match self {
+            Compat::NetworkdDispatcher => match state_type {
+                StateType::Operational | StateType::Administrative => {
+                    Some(vec![PathBuf::from(format!("{state}.d"))])
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// Environment variables which scripts of the other dispatcher expect
+    pub fn env_vars(
+        &self,
+        iface: &str,
+        state: &str,
+        link_details: &LinkDetails,
+        json: &str,
+    ) -> Vec<EnvVar> {
+        match self {
+            Compat::NetworkdDispatcher => {
+                let ips = link_details
+                    .addresses
+                    .iter()
+                    .filter_map(|address| address.ip())
+                    .collect::<Vec<_>>();
+                let ipv4_addrs = ips
+                    .iter()
+                    .filter(|ip| ip.is_ipv4())
+                    .map(|ip| ip.to_string())
+                    .collect::<Vec<_>>();
+                let ipv6_addrs = ips
+                    .iter()
+                    .filter(|ip| ip.is_ipv6())
+                    .map(|ip| ip.to_string())
+                    .collect::<Vec<_>>();
+
+                let unprefixed = |key: &str, value: String| EnvVar::Unprefixed {
+                    key: key.to_string(),
+                    value,
+                };
+                vec![
+                    unprefixed("IFACE", iface.to_string()),
+                    unprefixed("STATE", state.to_string()),
+                    unprefixed("ADDR", ipv4_addrs.first().cloned().unwrap_or_default()),
+                    unprefixed("IP_ADDRS", ipv4_addrs.join(" ")),
+                    unprefixed("IP6_ADDRS", ipv6_addrs.join(" ")),
+                    unprefixed("ESSID", link_details.ssid.clone().unwrap_or_default()),
+                    unprefixed(
+                        "AdministrativeState",
+                        link_details
+                            .state(StateType::Administrative)
+                            .unwrap_or_default(),
+                    ),
+                    unprefixed(
+                        "OperationalState",
+                        link_details
+                            .state(StateType::Operational)
+                            .unwrap_or_default(),
+                    ),
+                    unprefixed("json", json.to_string()),
+                ]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_networkd_dispatcher_script_paths() {
+        let compat = Compat::NetworkdDispatcher;
+        assert_eq!(
+            compat.script_paths(StateType::Operational, "routable"),
+            Some(vec![PathBuf::from("routable.d")])
+        );
+        assert_eq!(
+            compat.script_paths(StateType::Administrative, "configured"),
+            Some(vec![PathBuf::from("configured.d")])
+        );
+        assert_eq!(compat.script_paths(StateType::Carrier, "carrier"), None);
+    }
+
+    #[test]
+    fn test_networkd_dispatcher_env_vars() {
+        let json = r#"{
+            "OperationalState": "routable",
+            "SSID": "HomeNetwork",
+            "Addresses": [
+                { "Family": 2, "Address": [192, 168, 1, 101] },
+                { "Family": 10, "Address": [254, 128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] },
+                { "Family": 2, "Address": [10, 0, 0, 5] }
+            ]
+        }"#;
+        let link_details = serde_json::from_str::<LinkDetails>(json).unwrap();
+        let env_vars = Compat::NetworkdDispatcher
+            .env_vars("wlp3s0", "routable", &link_details, json)
+            .iter()
+            .map(|env_var| {
+                let EnvVar::Unprefixed { key, value } = env_var else {
+                    panic!("{env_var:?} is not unprefixed");
+                };
+                (key.clone(), value.clone())
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            ("IFACE", "wlp3s0"),
+            ("STATE", "routable"),
+            ("ADDR", "192.168.1.101"),
+            ("IP_ADDRS", "192.168.1.101 10.0.0.5"),
+            ("IP6_ADDRS", "fe80::1"),
+            ("ESSID", "HomeNetwork"),
+            ("AdministrativeState", ""),
+            ("OperationalState", "routable"),
+            ("json", json),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<Vec<_>>();
+        assert_eq!(env_vars, expected);
+    }
+}
@@ -0,0 +1,581 @@
+//! # Configuration file
+//!
+//! `/etc/networkd/broker.conf` declares hook rules in TOML, alongside the scripts which are found
+//! in the script directories. Each `[[hook]]` matches link events by their state and link
+//! details, and runs a script or an inline shell command, e.g.
+//!
+//! ```toml
+//! [[hook]]
+//! name = "wifi-vpn"
+//! state = "routable"
+//! iface = "wl*"
+//! ssid = ["Cafe*", "Airport"]
+//! command = "systemctl start vpn.service"
+//! timeout = "30s"
+//! env = { VPN_PROFILE = "travel" }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io,
+    os::unix::fs::MetadataExt,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use nix::unistd::Uid;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    allowlist::{
+        Allowlist,
+        audit_refused,
+    },
+    link::StateType,
+    link_details::LinkDetails,
+    run_as::RunAs,
+    script::{
+        EnvVar,
+        Script,
+        ScriptBuilder,
+        check_file_trusted,
+    },
+    script_config::{
+        ScriptConfig,
+        parse_seconds,
+        parse_signal,
+    },
+};
+
+pub const DEFAULT_CONFIG_FILE: &str = "/etc/networkd/broker.conf";
+
+/// Content of the configuration file
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Hook rules, in the order of the file
+    pub hooks: Vec<Hook>,
+}
+
+impl Config {
+    /// Read the configuration file, a missing file is an empty configuration
+    ///
+    /// The configuration file and hook scripts are trusted the same as scripts of the script
+    /// directories, they must be owned by root and not writable by anyone else. A hook script
+    /// which is not trusted is left out.
+    ///
+    /// * `allowlist` - Approved digests of the configuration file, which has inline commands,
+    ///   and of hook scripts. A hook script which is not approved is left out.
+    ///
+    pub fn load(path: &Path, allowlist: Option<&Allowlist>) -> Result<Config> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("`{}` does not exist", path.display());
+                return Ok(Config::default());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read `{}`", path.display()));
+            }
+        };
+        // Root, or the user which the broker runs as
+        let uid = Uid::effective().as_raw();
+        if let Err(err) = check_file_trusted(path, uid) {
+            audit_refused(path, &err);
+            bail!("Refuse to load `{}`. {err:#}", path.display());
+        }
+        if let Some(allowlist) = allowlist
+            && let Err(err) = allowlist.verify_content(path, content.as_bytes())
+        {
+            audit_refused(path, &err);
+            bail!("Refuse to load `{}`. {err:#}", path.display());
+        }
+
+        let mut config = Config::parse(&content)
+            .with_context(|| format!("Invalid config `{}`", path.display()))?;
+        config.hooks.retain_mut(|hook| {
+            let Program::Script(script) = &hook.program else {
+                return true;
+            };
+            match Hook::check_script(script, uid, allowlist) {
+                Ok(digest) => {
+                    hook.digest = digest;
+                    true
+                }
+                Err(err) => {
+                    audit_refused(script, &err);
+                    false
+                }
+            }
+        });
+        Ok(config)
+    }
+
+    /// Scripts which hooks run
+    pub fn scripts(&self) -> impl Iterator<Item = &Path> {
+        self.hooks.iter().filter_map(|hook| match &hook.program {
+            Program::Script(script) => Some(script.as_path()),
+            Program::Command(_) => None,
+        })
+    }
+
+    fn parse(content: &str) -> Result<Config> {
+        let file = toml::from_str::<ConfigFile>(content)?;
+        let hooks = file
+            .hook
+            .into_iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                let name = rule
+                    .name
+                    .clone()
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or_else(|| format!("hook-{index}"));
+                Hook::new(name.clone(), rule).with_context(|| format!("Invalid hook `{name}`"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Config { hooks })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    hook: Vec<HookRule>,
+}
+
+/// A `[[hook]]` table as it is written
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct HookRule {
+    name: Option<String>,
+
+    state_type: Option<Patterns>,
+    state: Option<Patterns>,
+    previous_state: Option<Patterns>,
+    iface: Option<Patterns>,
+    kind: Option<Patterns>,
+    #[serde(rename = "type")]
+    link_type: Option<Patterns>,
+    driver: Option<Patterns>,
+    ssid: Option<Patterns>,
+    network_file: Option<Patterns>,
+
+    script: Option<PathBuf>,
+    command: Option<String>,
+
+    timeout: Option<Seconds>,
+    nowait: Option<bool>,
+    kill_signal: Option<String>,
+    grace: Option<Seconds>,
+    user: Option<String>,
+    group: Option<String>,
+
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+/// Duration as a number of seconds or a string, e.g. `120` or `"2m"`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Seconds {
+    Number(u64),
+    Text(String),
+}
+
+impl Seconds {
+    fn parse(&self) -> Result<u64> {
+        match self {
+            Seconds::Number(seconds) => Ok(*seconds),
+            Seconds::Text(value) => parse_seconds(value),
+        }
+    }
+}
+
+/// One glob pattern or a list of them, a value matches if it matches any of them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Patterns {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Patterns {
+    /// Whether a value matches, an unknown value matches nothing
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        let Some(value) = value else {
+            return false;
+        };
+        match self {
+            Patterns::One(pattern) => glob_match(pattern, value),
+            Patterns::Many(patterns) => patterns.iter().any(|pattern| glob_match(pattern, value)),
+        }
+    }
+}
+
+/// What a hook runs
+#[derive(Debug, Clone)]
+enum Program {
+    Script(PathBuf),
+    Command(String),
+}
+
+/// A validated hook rule
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub name: String,
+
+    /// Without it, the hook responds to operational states and actions, like scripts of the
+    /// script root directory
+    state_type: Option<Patterns>,
+    state: Option<Patterns>,
+    previous_state: Option<Patterns>,
+    iface: Option<Patterns>,
+    kind: Option<Patterns>,
+    link_type: Option<Patterns>,
+    driver: Option<Patterns>,
+    ssid: Option<Patterns>,
+    network_file: Option<Patterns>,
+
+    program: Program,
+    config: ScriptConfig,
+    run_as: Option<RunAs>,
+    env: BTreeMap<String, String>,
+
+    /// SHA-256 digest of the script in the allowlist
+    digest: Option<String>,
+}
+
+impl Hook {
+    fn new(name: String, rule: HookRule) -> Result<Hook> {
+        let program = match (rule.script, rule.command) {
+            (Some(script), None) if script.is_absolute() => Program::Script(script),
+            (Some(script), None) => bail!("`{}` is not an absolute path", script.display()),
+            (None, Some(command)) => Program::Command(command),
+            _ => bail!("Exactly one of `script` and `command` is required"),
+        };
+
+        let config = ScriptConfig {
+            timeout: rule.timeout.as_ref().map(Seconds::parse).transpose()?,
+            nowait: rule.nowait,
+            kill_signal: rule.kill_signal.as_deref().map(parse_signal).transpose()?,
+            grace: rule.grace.as_ref().map(Seconds::parse).transpose()?,
+            user: rule.user,
+            group: rule.group,
+        };
+        // Unknown users fail when the config is loaded, not when the hook is run
+        let run_as = RunAs::from_settings(config.user.as_deref(), config.group.as_deref())?;
+
+        if let Some(key) = rule.env.keys().find(|key| !is_env_name(key)) {
+            bail!("Invalid environment variable name `{key}`");
+        }
+
+        Ok(Hook {
+            name,
+            state_type: rule.state_type,
+            state: rule.state,
+            previous_state: rule.previous_state,
+            iface: rule.iface,
+            kind: rule.kind,
+            link_type: rule.link_type,
+            driver: rule.driver,
+            ssid: rule.ssid,
+            network_file: rule.network_file,
+            program,
+            config,
+            run_as,
+            env: rule.env,
+            digest: None,
+        })
+    }
+
+    /// Whether the hook matches an event by its state and link name. Conditions on link details
+    /// are checked by `matches_link`.
+    ///
+    /// * `state_type` - Type of a changed state, or none for an action, e.g. `added`
+    ///
+    pub fn matches_event(
+        &self,
+        state_type: Option<StateType>,
+        state: &str,
+        previous_state: Option<&str>,
+        iface: &str,
+    ) -> bool {
+        let matches = |patterns: &Option<Patterns>, value: Option<&str>| {
+            patterns
+                .as_ref()
+                .is_none_or(|patterns| patterns.matches(value))
+        };
+        let state_type_matches = match &self.state_type {
+            Some(patterns) => patterns.matches(state_type.map(|t| t.to_string()).as_deref()),
+            None => state_type.is_none_or(|state_type| state_type == StateType::Operational),
+        };
+        state_type_matches
+            && matches(&self.state, Some(state))
+            && matches(&self.previous_state, previous_state)
+            && matches(&self.iface, Some(iface))
+    }
+
+    /// Whether the hook has any condition on link details
+    pub fn needs_link_details(&self) -> bool {
+        [
+            &self.kind,
+            &self.link_type,
+            &self.driver,
+            &self.ssid,
+            &self.network_file,
+        ]
+        .iter()
+        .any(|patterns| patterns.is_some())
+    }
+
+    /// Whether the hook matches details of a link. Without details, only a hook without such
+    /// conditions matches.
+    pub fn matches_link(&self, link_details: Option<&LinkDetails>) -> bool {
+        let Some(link_details) = link_details else {
+            return !self.needs_link_details();
+        };
+        let matches = |patterns: &Option<Patterns>, value: &Option<String>| {
+            patterns
+                .as_ref()
+                .is_none_or(|patterns| patterns.matches(value.as_deref()))
+        };
+        matches(&self.kind, &link_details.kind)
+            && matches(&self.link_type, &link_details.link_type)
+            && matches(&self.driver, &link_details.driver)
+            && matches(&self.ssid, &link_details.ssid)
+            && matches(&self.network_file, &link_details.network_file)
+    }
+
+    /// Check a hook script like a script of the script directories, return its approved digest
+    fn check_script(
+        script: &Path,
+        uid: u32,
+        allowlist: Option<&Allowlist>,
+    ) -> Result<Option<String>> {
+        let metadata = check_file_trusted(script, uid)?;
+        // Has at least 500 for file mode
+        if metadata.mode() & 0o500 != 0o500 {
+            bail!("It is not executable");
+        }
+        allowlist
+            .map(|allowlist| allowlist.verify(script))
+            .transpose()
+    }
+
+    /// Script which runs the hook, its args and link environment variables are set by the caller
+    pub fn builder(&self) -> ScriptBuilder {
+        let script = match &self.program {
+            Program::Script(path) => Script::builder().set_path(path),
+            // The script is known by the name of the hook
+            Program::Command(command) => Script::builder()
+                .set_path(Path::new(&self.name))
+                .set_inline(command),
+        };
+        let script = self.env.iter().fold(
+            script.set_config(self.config.clone()),
+            |script, (key, value)| {
+                script.add_env(EnvVar::Unprefixed {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+            },
+        );
+        let script = match &self.digest {
+            Some(digest) => script.set_digest(digest),
+            None => script,
+        };
+        match &self.run_as {
+            Some(run_as) => script.set_run_as(run_as.clone()),
+            None => script,
+        }
+    }
+}
+
+fn is_env_name(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with(|c: char| c.is_ascii_digit())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Match a value against a glob pattern, `*` matches any sequence and `?` matches any character
+fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let value = value.chars().collect::<Vec<_>>();
+    let (mut p, mut v) = (0, 0);
+    // Position of the last `*` in the pattern, and of the value where it starts matching
+    let mut backtrack = None;
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                v += 1;
+            }
+            Some(c) if *c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, start)) => {
+                    // Let the `*` match one more character
+                    p = star + 1;
+                    v = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("wlan0", "wlan0"));
+        assert!(!glob_match("wlan0", "wlan1"));
+        assert!(glob_match("wl*", "wlp3s0"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*s0", "wlp3s0"));
+        assert!(glob_match("wl?3*0", "wlp3s0"));
+        assert!(!glob_match("wl?", "wlp3s0"));
+        assert!(glob_match("*a*b", "xaab"));
+        assert!(!glob_match("*a*b", "xaba"));
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            [[hook]]
+            name = "wifi-vpn"
+            state = "routable"
+            previous-state = ["degraded", "carrier"]
+            iface = "wl*"
+            ssid = ["Cafe*", "Airport"]
+            command = "systemctl start vpn.service"
+            timeout = "2m"
+            grace = 3
+            kill-signal = "INT"
+            env = { VPN_PROFILE = "travel" }
+
+            [[hook]]
+            state-type = ["CarrierState", "AddressState"]
+            state = "*"
+            type = "ether"
+            script = "/usr/local/bin/log-link"
+            nowait = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.hooks.len(), 2);
+
+        let wifi = &config.hooks[0];
+        assert_eq!(wifi.name, "wifi-vpn");
+        assert_eq!(
+            wifi.config,
+            ScriptConfig {
+                timeout: Some(120),
+                grace: Some(3),
+                kill_signal: Some(nix::sys::signal::Signal::SIGINT),
+                ..Default::default()
+            }
+        );
+        let operational = Some(StateType::Operational);
+        assert!(wifi.matches_event(operational, "routable", Some("degraded"), "wlp3s0"));
+        assert!(!wifi.matches_event(operational, "routable", None, "wlp3s0"));
+        assert!(!wifi.matches_event(operational, "routable", Some("degraded"), "enp1s0"));
+        assert!(!wifi.matches_event(operational, "degraded", Some("carrier"), "wlp3s0"));
+        let address = Some(StateType::Address);
+        assert!(!wifi.matches_event(address, "routable", Some("degraded"), "wlp3s0"));
+
+        let cafe = serde_json::from_str::<LinkDetails>(r#"{"SSID": "CafeNet"}"#).unwrap();
+        let home = serde_json::from_str::<LinkDetails>(r#"{"SSID": "HomeNetwork"}"#).unwrap();
+        assert!(wifi.matches_link(Some(&cafe)));
+        assert!(!wifi.matches_link(Some(&home)));
+        assert!(!wifi.matches_link(None));
+
+        let ether = &config.hooks[1];
+        assert_eq!(ether.name, "hook-1");
+        assert!(ether.matches_event(Some(StateType::Carrier), "off", None, "enp1s0"));
+        assert!(!ether.matches_event(operational, "off", None, "enp1s0"));
+        assert!(!ether.matches_event(None, "added", None, "enp1s0"));
+        assert!(!ether.matches_link(Some(&cafe)));
+        assert!(ether.matches_link(Some(
+            &serde_json::from_str::<LinkDetails>(r#"{"Type": "ether"}"#).unwrap()
+        )));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for content in [
+            // Neither script nor command
+            "[[hook]]\nstate = \"routable\"",
+            // Both script and command
+            "[[hook]]\nscript = \"/bin/true\"\ncommand = \"true\"",
+            "[[hook]]\nscript = \"relative\"",
+            "[[hook]]\ncommand = \"true\"\ntimeout = \"soon\"",
+            "[[hook]]\ncommand = \"true\"\nuser = \"no-such-user\"",
+            "[[hook]]\ncommand = \"true\"\nenv = { \"NOT-A-NAME\" = \"1\" }",
+            "[[hook]]\ncommand = \"true\"\nunknown = 1",
+        ] {
+            assert!(Config::parse(content).is_err(), "{content}");
+        }
+        assert!(Config::parse("").unwrap().hooks.is_empty());
+    }
+
+    #[test]
+    fn test_load_untrusted_config() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let create = |name: &str, content: &str, mode: u32| {
+            let path = temp_dir.path().join(name);
+            fs::write(&path, content).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            path
+        };
+        let trusted = create("trusted", "#!/bin/sh\n", 0o755);
+        let group_writable = create("group-writable", "#!/bin/sh\n", 0o775);
+        let not_executable = create("not-executable", "#!/bin/sh\n", 0o644);
+        let config = create(
+            "broker.conf",
+            &[&trusted, &group_writable, &not_executable]
+                .iter()
+                .map(|script| format!("[[hook]]\nscript = \"{}\"\n", script.display()))
+                .collect::<String>(),
+            0o644,
+        );
+
+        // Untrusted hook scripts are left out
+        let loaded = Config::load(&config, None).unwrap();
+        assert_eq!(loaded.scripts().collect::<Vec<_>>(), [trusted.as_path()]);
+
+        // A group-writable configuration file is refused
+        fs::set_permissions(&config, fs::Permissions::from_mode(0o664)).unwrap();
+        assert!(Config::load(&config, None).is_err());
+
+        assert!(
+            Config::load(&temp_dir.path().join("missing.conf"), None)
+                .unwrap()
+                .hooks
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! # Flap detection
+//!
+//! A link which changes its states more than a threshold within a window is flapping. Scripts
+//! of `flapping.d` are run once, then the link is not responded to until it is stable for a
+//! cooldown period. Scripts of `stable.d` are run, followed by scripts of the net changes of link
+//! states since the link starts flapping.
+
+use std::{
+    collections::VecDeque,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use crate::link::LinkStates;
+
+pub const DEFAULT_FLAP_WINDOW: u64 = 60; // seconds
+pub const DEFAULT_FLAP_COOLDOWN: u64 = 120; // seconds
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlapDetection {
+    /// A link is flapping when it has more transitions than this within `window`
+    threshold: usize,
+    window: Duration,
+
+    /// A flapping link is stable when it has no transition for this long
+    cooldown: Duration,
+}
+
+impl FlapDetection {
+    pub fn new(threshold: usize, window: Duration, cooldown: Duration) -> FlapDetection {
+        FlapDetection {
+            threshold,
+            window,
+            cooldown,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+}
+
+/// Recent transitions of a link
+#[derive(Debug, Clone, Default)]
+pub struct FlapHistory {
+    transitions: VecDeque<Instant>,
+    flapping: Option<Flapping>,
+}
+
+#[derive(Debug, Clone)]
+struct Flapping {
+    /// The states which scripts are last run for before the link starts flapping
+    states: LinkStates,
+
+    /// Number of transitions since the link starts flapping, including those which start it
+    count: usize,
+}
+
+impl FlapHistory {
+    /// Record a transition of a link. Return the number of transitions within the window when
+    /// the link starts flapping.
+    ///
+    /// * `states` - The states which scripts are last run for
+    ///
+    pub fn record(
+        &mut self,
+        detection: &FlapDetection,
+        now: Instant,
+        states: LinkStates,
+    ) -> Option<usize> {
+        self.transitions.push_back(now);
+        if let Some(flapping) = &mut self.flapping {
+            flapping.count += 1;
+            self.transitions.drain(..self.transitions.len() - 1);
+            return None;
+        }
+
+        while self
+            .transitions
+            .front()
+            .is_some_and(|transition| now.duration_since(*transition) > detection.window)
+        {
+            self.transitions.pop_front();
+        }
+        if self.transitions.len() <= detection.threshold {
+            return None;
+        }
+
+        let count = self.transitions.len();
+        self.flapping = Some(Flapping { states, count });
+        Some(count)
+    }
+
+    pub fn is_flapping(&self) -> bool {
+        self.flapping.is_some()
+    }
+
+    /// The states before a flapping link starts flapping
+    pub fn flapping_states(&self) -> Option<&LinkStates> {
+        self.flapping.as_ref().map(|flapping| &flapping.states)
+    }
+
+    /// When a flapping link becomes stable if it has no more transition
+    pub fn stable_deadline(&self, detection: &FlapDetection) -> Option<Instant> {
+        self.flapping.as_ref()?;
+        self.transitions
+            .back()
+            .map(|transition| *transition + detection.cooldown)
+    }
+
+    /// Take the states before a flapping link starts flapping, and its number of transitions,
+    /// when it becomes stable
+    pub fn take_stable(
+        &mut self,
+        detection: &FlapDetection,
+        now: Instant,
+    ) -> Option<(LinkStates, usize)> {
+        if self.stable_deadline(detection)? > now {
+            return None;
+        }
+        self.transitions.clear();
+        self.flapping
+            .take()
+            .map(|flapping| (flapping.states, flapping.count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::StateType;
+
+    #[test]
+    fn test_flap_history() {
+        let detection = FlapDetection::new(3, Duration::from_secs(10), Duration::from_secs(30));
+        let states = LinkStates::from([(StateType::Operational, "routable".to_string())]);
+        let mut history = FlapHistory::default();
+        let start = Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        // Transitions out of the window are not counted
+        for seconds in [0, 11, 12, 13] {
+            assert_eq!(
+                history.record(&detection, at(seconds), states.clone()),
+                None
+            );
+        }
+        assert!(!history.is_flapping());
+        assert_eq!(history.stable_deadline(&detection), None);
+
+        // 4 transitions within 10 seconds
+        assert_eq!(history.record(&detection, at(14), states.clone()), Some(4));
+        assert!(history.is_flapping());
+        assert_eq!(history.stable_deadline(&detection), Some(at(44)));
+
+        // Further transitions extend the cooldown
+        assert_eq!(history.record(&detection, at(40), LinkStates::new()), None);
+        assert_eq!(history.take_stable(&detection, at(60)), None);
+        assert_eq!(
+            history.take_stable(&detection, at(70)),
+            Some((states.clone(), 5))
+        );
+        assert!(!history.is_flapping());
+        assert_eq!(history.record(&detection, at(71), states), None);
+    }
+}
@@ -0,0 +1,276 @@
+//! # Structured logging to systemd journal
+//!
+//! Events are sent to journald with the native journal protocol. Fields whose names start with
+//! `nwd_` are attached to the record as journal fields, e.g. `nwd_script` becomes `NWD_SCRIPT=`,
+//! so `journalctl NWD_SCRIPT=...` finds every record of a script.
+//! On other outputs, these fields are hidden by [`HideJournalFields`].
+
+use std::fmt::{
+    self,
+    Write,
+};
+
+use libsystemd::logging::{
+    Priority,
+    journal_send,
+};
+use tracing::{
+    Event,
+    Level,
+    Subscriber,
+    field::{
+        Field,
+        Visit,
+    },
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{
+        FormatFields,
+        format::Writer,
+    },
+    layer::{
+        Context,
+        Layer,
+    },
+};
+
+/// Prefix of field names which are sent as journal fields
+const JOURNAL_FIELD_PREFIX: &str = "nwd_";
+
+/// Send events to systemd journal
+pub struct JournalLayer;
+
+impl<S: Subscriber> Layer<S> for JournalLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let record = JournalRecord::new(event);
+        let fields = record.fields.iter().map(|(key, value)| (key, value));
+        if let Err(err) = journal_send(record.priority, &record.message, fields) {
+            // Nothing else to report to
+            eprintln!("Failed to send log to journal: {err}");
+        }
+    }
+}
+
+/// Message and fields of an event in the form of journal entry
+#[derive(Debug)]
+struct JournalRecord {
+    priority: Priority,
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl JournalRecord {
+    fn new(event: &Event<'_>) -> JournalRecord {
+        let metadata = event.metadata();
+        let mut visitor = JournalVisitor::default();
+        event.record(&mut visitor);
+
+        let mut fields = vec![
+            (
+                "SYSLOG_IDENTIFIER".to_string(),
+                env!("CARGO_PKG_NAME").to_string(),
+            ),
+            ("TARGET".to_string(), metadata.target().to_string()),
+        ];
+        if let Some(file) = metadata.file() {
+            fields.push(("CODE_FILE".to_string(), file.to_string()));
+        }
+        if let Some(line) = metadata.line() {
+            fields.push(("CODE_LINE".to_string(), line.to_string()));
+        }
+        fields.extend(visitor.fields);
+
+        JournalRecord {
+            priority: priority(metadata.level()),
+            message: visitor.message,
+            fields,
+        }
+    }
+}
+
+fn priority(level: &Level) -> Priority {
+    match *level {
+        Level::ERROR => Priority::Error,
+        Level::WARN => Priority::Warning,
+        Level::INFO => Priority::Info,
+        Level::DEBUG | Level::TRACE => Priority::Debug,
+    }
+}
+
+/// Collect message and journal fields of an event.
+/// Other fields are appended to the message, as they are on other outputs.
+#[derive(Default)]
+struct JournalVisitor {
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for JournalVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name().starts_with(JOURNAL_FIELD_PREFIX) {
+            self.fields
+                .push((field.name().to_ascii_uppercase(), value.to_string()));
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+        } else if field.name().starts_with(JOURNAL_FIELD_PREFIX) {
+            self.fields
+                .push((field.name().to_ascii_uppercase(), format!("{value:?}")));
+        } else {
+            let _ = write!(self.message, " {}={value:?}", field.name());
+        }
+    }
+}
+
+/// Format fields of events like the default formatter, without journal fields
+pub struct HideJournalFields;
+
+impl<'writer> FormatFields<'writer> for HideJournalFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        let mut visitor = HideJournalFieldsVisitor {
+            writer,
+            is_empty: true,
+            result: Ok(()),
+        };
+        fields.record(&mut visitor);
+        visitor.result
+    }
+}
+
+struct HideJournalFieldsVisitor<'writer> {
+    writer: Writer<'writer>,
+    is_empty: bool,
+    result: fmt::Result,
+}
+
+impl Visit for HideJournalFieldsVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.result.is_err() || field.name().starts_with(JOURNAL_FIELD_PREFIX) {
+            return;
+        }
+
+        let separator = if self.is_empty { "" } else { " " };
+        self.is_empty = false;
+        self.result = if field.name() == "message" {
+            write!(self.writer, "{separator}{value:?}")
+        } else {
+            write!(self.writer, "{separator}{}={value:?}", field.name())
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
+    use tracing::{
+        info,
+        warn,
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    struct CaptureLayer(Arc<Mutex<Vec<JournalRecord>>>);
+
+    impl<S: Subscriber> Layer<S> for CaptureLayer {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            self.0.lock().unwrap().push(JournalRecord::new(event));
+        }
+    }
+
+    #[test]
+    fn test_journal_record() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(CaptureLayer(Arc::clone(&records)));
+        tracing::subscriber::with_default(subscriber, || {
+            let script = "/etc/networkd/broker.d/routable.d/10-chrony-switch";
+            info!(
+                nwd_script = script,
+                nwd_iface = "wlp3s0",
+                nwd_exit_status = 0,
+                "Finished executing {script}"
+            );
+            warn!(count = 2, "Something happened");
+        });
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+
+        assert!(matches!(records[0].priority, Priority::Info));
+        assert_eq!(
+            records[0].message,
+            "Finished executing /etc/networkd/broker.d/routable.d/10-chrony-switch"
+        );
+        let field = |name: &str| {
+            records[0]
+                .fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(
+            field("NWD_SCRIPT"),
+            Some("/etc/networkd/broker.d/routable.d/10-chrony-switch")
+        );
+        assert_eq!(field("NWD_IFACE"), Some("wlp3s0"));
+        assert_eq!(field("NWD_EXIT_STATUS"), Some("0"));
+        assert_eq!(field("SYSLOG_IDENTIFIER"), Some("networkd-broker"));
+
+        assert!(matches!(records[1].priority, Priority::Warning));
+        assert_eq!(records[1].message, "Something happened count=2");
+    }
+
+    #[test]
+    fn test_hide_journal_fields() {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let writer = {
+            let output = Arc::clone(&output);
+            move || SharedWriter(Arc::clone(&output))
+        };
+        let subscriber = tracing_subscriber::fmt()
+            .without_time()
+            .with_ansi(false)
+            .fmt_fields(HideJournalFields)
+            .with_writer(writer)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            info!(
+                nwd_script = "00-script",
+                nwd_iface = "wlp3s0",
+                "Execute 00-script"
+            );
+            info!(nwd_script = "00-script", count = 2, "Execute 00-script");
+        });
+
+        assert_eq!(
+            String::from_utf8(output.lock().unwrap().clone()).unwrap(),
+            format!(
+                " INFO {target}: Execute 00-script\n INFO {target}: Execute 00-script count=2\n",
+                target = module_path!()
+            )
+        );
+    }
+
+    struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -1,8 +1,22 @@
+//! # Script launcher
+//!
+//! Scripts of each link run one by one, in the order they are added, so scripts of one event
+//! still run in alphabetical order. Scripts of different links run in parallel on a fixed number
+//! of worker threads, so a slow script of one link does not hold up the others.
+//!
+//! When a newer event of a link arrives, scripts of its older events may be dropped or
+//! cancelled, see [`SupersedePolicy`].
+
 use std::{
-    sync::mpsc::{
-        RecvError,
-        Sender,
-        channel,
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    num::NonZeroUsize,
+    sync::{
+        Arc,
+        Condvar,
+        Mutex,
     },
     thread,
 };
@@ -11,49 +25,393 @@ use anyhow::{
     Context,
     Result,
 };
+use clap::ValueEnum;
 use tracing::{
     debug,
-    error,
+    info,
     warn,
 };
 
-use crate::script::Script;
+use crate::script::{
+    CancelToken,
+    Script,
+};
+
+pub const DEFAULT_MAX_CONCURRENCY: NonZeroUsize = NonZeroUsize::new(4).unwrap();
+
+/// What to do with scripts of older events of a link when a newer event arrives
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SupersedePolicy {
+    /// Run scripts of every event
+    #[default]
+    RunAll,
+
+    /// Drop scripts of older events which have not started yet
+    DropPending,
+
+    /// Drop scripts of older events which have not started yet, and cancel the running one
+    CancelRunning,
+}
 
 #[derive(Debug)]
 pub struct Launcher {
-    tx: Sender<Box<Script>>,
+    queues: Arc<Queues>,
+    policy: SupersedePolicy,
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    state: Mutex<QueueState>,
+
+    /// Notified when a link is pushed to `QueueState::ready`
+    ready: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    /// Scripts which are waiting to run, keyed by ifindex, so scripts of a renamed link still
+    /// run one by one
+    pending: HashMap<i32, VecDeque<Script>>,
+
+    /// Links which have pending scripts and no running script, in the order they become ready
+    ready: VecDeque<i32>,
+
+    /// Links which have a running script
+    running: HashMap<i32, Running>,
+}
+
+#[derive(Debug)]
+struct Running {
+    script: String,
+    event_id: u64,
+    cancel: CancelToken,
 }
 
 impl Launcher {
-    pub fn new() -> Result<Self> {
-        let (tx, rx) = channel::<Box<Script>>();
-
-        thread::Builder::new()
-            .name("script launcher".to_string())
-            .spawn(move || {
-                loop {
-                    match rx.recv() {
-                        Ok(script) => {
-                            debug!("Received a script {script:?}");
-                            if let Err(err) = script.execute().context("Failed to execute script") {
-                                warn!("{err:#}");
-                            }
-                        }
-                        Err(RecvError {}) => {
-                            error!("Failed to receive script");
-                        }
-                    };
-                }
-            })
-            .context("Could not create script launcher thread")?;
+    /// Start launcher with `max_concurrency` workers, which is the maximum number of scripts
+    /// running at the same time
+    pub fn new(max_concurrency: NonZeroUsize, policy: SupersedePolicy) -> Result<Self> {
+        let queues = Arc::new(Queues::default());
+
+        for worker in 0..max_concurrency.get() {
+            let queues = Arc::clone(&queues);
+            thread::Builder::new()
+                .name(format!("script launcher {worker}"))
+                .spawn(move || queues.run())
+                .context("Could not create script launcher thread")?;
+        }
 
-        Ok(Launcher { tx })
+        Ok(Launcher { queues, policy })
     }
 
     pub fn add(&self, script: Script) -> Result<()> {
-        self.tx
-            .send(Box::new(script))
-            .context("Failed to send a script to launcher channel")?;
+        let index = script.ifindex();
+        let mut state = self.queues.state.lock().unwrap();
+        self.supersede(&mut state, index, script.event_id());
+        let pending = state.pending.entry(index).or_default();
+        pending.push_back(script);
+        if pending.len() == 1 && !state.running.contains_key(&index) {
+            state.ready.push_back(index);
+            self.queues.ready.notify_one();
+        }
         Ok(())
     }
+
+    /// Apply supersede policy to scripts of older events of a link
+    fn supersede(&self, state: &mut QueueState, index: i32, event_id: u64) {
+        if self.policy == SupersedePolicy::RunAll {
+            return;
+        }
+
+        if let Some(pending) = state.pending.get_mut(&index) {
+            pending.retain(|script| {
+                if script.event_id() >= event_id {
+                    return true;
+                }
+                info!(
+                    "Drop {script} of event {}, superseded by event {event_id}",
+                    script.event_id()
+                );
+                false
+            });
+            if pending.is_empty() {
+                state.pending.remove(&index);
+                state.ready.retain(|ready| *ready != index);
+            }
+        }
+
+        if self.policy == SupersedePolicy::CancelRunning
+            && let Some(running) = state.running.get(&index)
+            && running.event_id < event_id
+            && !running.cancel.is_cancelled()
+        {
+            info!(
+                "Cancel {} of event {}, superseded by event {event_id}",
+                running.script, running.event_id
+            );
+            running.cancel.cancel();
+        }
+    }
+}
+
+impl Queues {
+    /// Worker loop, take the next script of a ready link and run it
+    fn run(&self) {
+        loop {
+            let (index, script) = self.next();
+            debug!("Received a script {script:?}");
+            if let Err(err) = script.execute().context("Failed to execute script") {
+                warn!("{err:#}");
+            }
+            self.done(index);
+        }
+    }
+
+    /// Wait for a ready link, then mark it as running
+    fn next(&self) -> (i32, Script) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(index) = state.ready.pop_front() {
+                let pending = state
+                    .pending
+                    .get_mut(&index)
+                    .expect("Ready link has pending scripts");
+                let script = pending.pop_front().expect("Ready link has pending scripts");
+                if pending.is_empty() {
+                    state.pending.remove(&index);
+                }
+                state.running.insert(
+                    index,
+                    Running {
+                        script: script.to_string(),
+                        event_id: script.event_id(),
+                        cancel: script.cancel_token(),
+                    },
+                );
+                return (index, script);
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Mark a link as not running, it goes back to the end of ready links if it has more
+    /// pending scripts
+    fn done(&self, index: i32) {
+        let mut state = self.state.lock().unwrap();
+        state.running.remove(&index);
+        if state.pending.contains_key(&index) {
+            state.ready.push_back(index);
+            self.ready.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::PathBuf,
+        time::{
+            Duration,
+            Instant,
+        },
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::script::EnvVar;
+
+    /// How long to wait for scripts before a test gives up
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    const WG0: (&str, i32) = ("wg0", 3);
+    const ETH0: (&str, i32) = ("eth0", 2);
+
+    /// Command of a script which holds it until the test releases it
+    const HOLD: &str = "until [ -e \"$DIR/$(basename \"$0\").release\" ]; do sleep 0.01; done";
+
+    /// Launcher of scripts which mark when they start, and append their names to a log when
+    /// they finish. Tests synchronize on these markers instead of wall-clock time.
+    struct Fixture {
+        temp_dir: TempDir,
+        launcher: Launcher,
+    }
+
+    impl Fixture {
+        fn new(max_concurrency: usize, policy: SupersedePolicy) -> Fixture {
+            Fixture {
+                temp_dir: TempDir::new().unwrap(),
+                launcher: Launcher::new(NonZeroUsize::new(max_concurrency).unwrap(), policy)
+                    .unwrap(),
+            }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.temp_dir.path().join(name)
+        }
+
+        fn add(&self, name: &str, (iface, index): (&str, i32), event_id: u64, command: &str) {
+            let path = self.path(name);
+            fs::write(
+                &path,
+                format!(
+                    "#!/usr/bin/env bash\ntouch \"$DIR/{name}.started\"\n{command}\necho {name} >> \"$DIR/log\"\n"
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            let script = Script::builder()
+                .set_path(&path)
+                .set_arg0("routable")
+                .set_arg1(iface)
+                .set_ifindex(index)
+                .set_event_id(event_id)
+                .add_env(EnvVar::Unprefixed {
+                    key: "DIR".to_string(),
+                    value: self.temp_dir.path().display().to_string(),
+                })
+                .build();
+            self.launcher.add(script).unwrap();
+        }
+
+        fn wait_started(&self, name: &str) {
+            let marker = self.path(&format!("{name}.started"));
+            let deadline = Instant::now() + WAIT_TIMEOUT;
+            while !marker.exists() {
+                assert!(Instant::now() < deadline, "{name} is not started");
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        fn release(&self, name: &str) {
+            fs::write(self.path(&format!("{name}.release")), "").unwrap();
+        }
+
+        /// Wait until `count` scripts finish, return their names in order
+        fn wait_log(&self, count: usize) -> Vec<String> {
+            let deadline = Instant::now() + WAIT_TIMEOUT;
+            loop {
+                let lines = fs::read_to_string(self.path("log"))
+                    .unwrap_or_default()
+                    .lines()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                if lines.len() >= count || Instant::now() > deadline {
+                    return lines;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    #[test]
+    fn test_links_run_in_parallel() {
+        // A held script of wg0 does not hold up eth0, scripts of wg0 still run in order
+        let fixture = Fixture::new(2, SupersedePolicy::RunAll);
+        fixture.add("10-wg0-slow", WG0, 1, HOLD);
+        fixture.add("20-wg0", WG0, 1, "");
+        fixture.wait_started("10-wg0-slow");
+        fixture.add("10-eth0", ETH0, 2, "");
+        assert_eq!(fixture.wait_log(1), ["10-eth0"]);
+        fixture.release("10-wg0-slow");
+        assert_eq!(fixture.wait_log(3), ["10-eth0", "10-wg0-slow", "20-wg0"]);
+
+        // With one worker, eth0 waits for the running script of wg0, then runs before the next
+        // script of wg0
+        let fixture = Fixture::new(1, SupersedePolicy::RunAll);
+        fixture.add("10-wg0-slow", WG0, 1, HOLD);
+        fixture.add("20-wg0", WG0, 1, "");
+        fixture.wait_started("10-wg0-slow");
+        fixture.add("10-eth0", ETH0, 2, "");
+        fixture.release("10-wg0-slow");
+        assert_eq!(fixture.wait_log(3), ["10-wg0-slow", "10-eth0", "20-wg0"]);
+    }
+
+    /// Start scripts of three events, the first one of wg0 and the one of eth0 are held
+    fn start_events(policy: SupersedePolicy) -> Fixture {
+        let fixture = Fixture::new(2, policy);
+        fixture.add("10-wg0-routable", WG0, 1, HOLD);
+        fixture.add("20-wg0-routable", WG0, 1, "");
+        fixture.wait_started("10-wg0-routable");
+        fixture.add("10-eth0-routable", ETH0, 2, HOLD);
+        fixture.wait_started("10-eth0-routable");
+        fixture.add("10-wg0-no-carrier", WG0, 3, "");
+        fixture
+    }
+
+    #[test]
+    fn test_supersede() {
+        let fixture = start_events(SupersedePolicy::RunAll);
+        fixture.release("10-wg0-routable");
+        assert_eq!(
+            fixture.wait_log(3),
+            ["10-wg0-routable", "20-wg0-routable", "10-wg0-no-carrier"]
+        );
+        fixture.release("10-eth0-routable");
+        assert_eq!(
+            fixture.wait_log(4),
+            [
+                "10-wg0-routable",
+                "20-wg0-routable",
+                "10-wg0-no-carrier",
+                "10-eth0-routable"
+            ]
+        );
+
+        // Pending script of the older event of wg0 is dropped, scripts of eth0 are kept
+        let fixture = start_events(SupersedePolicy::DropPending);
+        fixture.release("10-wg0-routable");
+        assert_eq!(
+            fixture.wait_log(2),
+            ["10-wg0-routable", "10-wg0-no-carrier"]
+        );
+        fixture.release("10-eth0-routable");
+        assert_eq!(
+            fixture.wait_log(3),
+            ["10-wg0-routable", "10-wg0-no-carrier", "10-eth0-routable"]
+        );
+
+        // Running script of the older event of wg0 is cancelled too, it is never released
+        let fixture = start_events(SupersedePolicy::CancelRunning);
+        assert_eq!(fixture.wait_log(1), ["10-wg0-no-carrier"]);
+        fixture.release("10-eth0-routable");
+        assert_eq!(
+            fixture.wait_log(2),
+            ["10-wg0-no-carrier", "10-eth0-routable"]
+        );
+    }
+
+    #[test]
+    fn test_scripts_of_one_event() {
+        // Scripts of every state change of one signal share its event, they do not supersede
+        // each other
+        let fixture = Fixture::new(2, SupersedePolicy::CancelRunning);
+        fixture.add("10-wg0-routable", WG0, 1, HOLD);
+        fixture.wait_started("10-wg0-routable");
+        fixture.add("10-wg0-carrier", WG0, 1, "");
+        {
+            let state = fixture.launcher.queues.state.lock().unwrap();
+            assert!(!state.running[&WG0.1].cancel.is_cancelled());
+            assert_eq!(state.pending[&WG0.1].len(), 1);
+        }
+        fixture.release("10-wg0-routable");
+        assert_eq!(fixture.wait_log(2), ["10-wg0-routable", "10-wg0-carrier"]);
+    }
+
+    #[test]
+    fn test_renamed_link() {
+        // Scripts of a link under its new name wait for the running one under its old name
+        let fixture = Fixture::new(2, SupersedePolicy::RunAll);
+        fixture.add("10-eth0", ETH0, 1, HOLD);
+        fixture.wait_started("10-eth0");
+        fixture.add("10-enp3s0", ("enp3s0", ETH0.1), 2, "");
+        assert_eq!(
+            fixture.launcher.queues.state.lock().unwrap().pending[&ETH0.1].len(),
+            1
+        );
+        fixture.release("10-eth0");
+        assert_eq!(fixture.wait_log(2), ["10-eth0", "10-enp3s0"]);
+    }
 }
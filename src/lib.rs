@@ -1,6 +1,18 @@
+pub mod allowlist;
 pub mod args;
 pub mod broker;
+pub mod compat;
+pub mod config;
+pub mod flap;
+pub mod journal;
 pub mod launcher;
 pub mod link;
+pub mod link_details;
 pub mod network_dbus;
+pub mod run_as;
 pub mod script;
+pub mod script_config;
+pub mod script_index;
+pub mod script_output;
+pub mod settle;
+pub mod state_file;
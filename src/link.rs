@@ -1,61 +1,203 @@
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    fmt,
+};
+
 use anyhow::{
     Context,
     Result,
     bail,
 };
-use serde::Deserialize;
+use nix::net::if_::if_nameindex;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use tracing::debug;
 use zbus::{
     Message,
     names::InterfaceName,
+    zvariant::OwnedValue,
 };
 
-use crate::network_dbus::NetworkManagerProxy;
+use crate::{
+    link_details::LinkDetails,
+    network_dbus::NetworkManagerProxy,
+};
 
-#[derive(Deserialize)]
-pub struct LinkDetails {
+/// Link state properties which scripts can respond to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum StateType {
+    #[serde(rename = "OperationalState")]
+    Operational,
+    #[serde(rename = "CarrierState")]
+    Carrier,
+    #[serde(rename = "AddressState")]
+    Address,
+    #[serde(rename = "IPv4AddressState")]
+    Ipv4Address,
+    #[serde(rename = "IPv6AddressState")]
+    Ipv6Address,
     #[serde(rename = "AdministrativeState")]
-    // systemd 249's Manager.DescribeLink() JSON, as shipped in Ubuntu 22.04,
-    // does not include this key, even though AdministrativeState is available
-    // as a property on org.freedesktop.network1.Link. systemd 255's
-    // DescribeLink() JSON does include it, so keep parsing it when present.
-    administrative_state: Option<String>,
+    Administrative,
+}
 
-    #[serde(rename = "OperationalState")]
-    pub operational_state: String,
+impl StateType {
+    pub const ALL: [StateType; 6] = [
+        StateType::Operational,
+        StateType::Carrier,
+        StateType::Address,
+        StateType::Ipv4Address,
+        StateType::Ipv6Address,
+        StateType::Administrative,
+    ];
 
-    #[serde(rename = "CarrierState")]
-    carrier_state: String,
+    /// Sub-directory of script root directory which holds `<STATE>.d` of this state type.
+    ///
+    /// `OperationalState` has no sub-directory, its `<STATE>.d` are directly under script root
+    /// directory.
+    pub fn dir(&self) -> Option<&'static str> {
+        match self {
+            StateType::Operational => None,
+            StateType::Carrier => Some("carrier-state"),
+            StateType::Address => Some("address-state"),
+            StateType::Ipv4Address => Some("ipv4-address-state"),
+            StateType::Ipv6Address => Some("ipv6-address-state"),
+            StateType::Administrative => Some("administrative-state"),
+        }
+    }
 
-    #[serde(rename = "AddressState")]
-    address_state: String,
+    /// Get state type from property name of `org.freedesktop.network1.Link`
+    pub fn from_property(property: &str) -> Option<StateType> {
+        StateType::ALL
+            .into_iter()
+            .find(|state_type| state_type.to_string() == property)
+    }
+}
 
-    #[serde(rename = "IPv4AddressState")]
-    ipv4_address_state: String,
+impl fmt::Display for StateType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateType::Operational => write!(f, "OperationalState"),
+            StateType::Carrier => write!(f, "CarrierState"),
+            StateType::Address => write!(f, "AddressState"),
+            StateType::Ipv4Address => write!(f, "IPv4AddressState"),
+            StateType::Ipv6Address => write!(f, "IPv6AddressState"),
+            StateType::Administrative => write!(f, "AdministrativeState"),
+        }
+    }
+}
 
-    #[serde(rename = "IPv6AddressState")]
-    ipv6_address_state: String,
+/// Appearance, disappearance or renaming of a link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkAction {
+    Added,
+    Removed,
+    Renamed,
+
+    /// A link changes too often, see [`crate::flap`]
+    Flapping,
+
+    /// A flapping link stops changing
+    Stable,
 }
 
-#[derive(Debug)]
-struct Link {
-    index: i32,
-    name: String,
-    _path: String,
+impl fmt::Display for LinkAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkAction::Added => write!(f, "added"),
+            LinkAction::Removed => write!(f, "removed"),
+            LinkAction::Renamed => write!(f, "renamed"),
+            LinkAction::Flapping => write!(f, "flapping"),
+            LinkAction::Stable => write!(f, "stable"),
+        }
+    }
 }
 
-/// Network link information which is extracted from DBus signal message
-pub struct LinkEvent {
+/// Current value of each state type of a link
+pub type LinkStates = BTreeMap<StateType, String>;
+
+/// Link details which are described by `DescribeLink`
+pub struct Link {
+    pub index: i32,
     pub iface: String,
-    pub state: String,
-    pub path: String,
     pub link_details: LinkDetails,
     pub link_details_json: String,
+
+    /// Serial number of `DescribeLink` reply.
+    /// Signals which are sent by systemd-networkd after this reply have greater serial numbers.
+    pub serial: u32,
+}
+
+impl Link {
+    /// Get link details of a link which is listed by `ListLinks`
+    pub async fn describe(
+        proxy: &NetworkManagerProxy<'_>,
+        index: i32,
+        iface: &str,
+    ) -> Result<Link> {
+        debug!("Get link details of {iface}");
+        let reply = proxy.inner().call_method("DescribeLink", &(index)).await?;
+        let serial = reply.primary_header().serial_num().get();
+        let describe_link: String = reply.body().deserialize()?;
+        let link_details = serde_json::from_str::<LinkDetails>(&describe_link)
+            .with_context(|| format!("Cannot get link details of `{iface}`"))?;
+        debug!(
+            "{}",
+            link_details
+                .states()
+                .iter()
+                .map(|(state_type, state)| format!("{state_type}: {state}"))
+                .collect::<Vec<_>>()
+                .join(",  ")
+        );
+
+        Ok(Link {
+            index,
+            iface: iface.to_string(),
+            link_details,
+            link_details_json: describe_link,
+            serial,
+        })
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} --> {}",
+            self.iface,
+            self.link_details
+                .operational_state
+                .as_ref()
+                .map_or("unavailable", |state| state.as_str())
+        )
+    }
+}
+
+/// Network link information which is extracted from DBus signal message
+#[derive(Debug)]
+pub struct LinkEvent {
+    pub index: i32,
+    pub path: String,
+
+    /// Serial number of the message which is assigned by its sender
+    pub serial: u32,
+
+    /// Changed link states which are included in the message
+    pub states: LinkStates,
+
+    /// Some link states are changed, but their values are not included in the message
+    pub invalidated: bool,
 }
 
 impl LinkEvent {
     /// Extract link event from DBus signal message
-    pub async fn new(msg: &Message, conn: &::zbus::Connection) -> Result<Box<LinkEvent>> {
+    pub fn new(msg: &Message) -> Result<Box<LinkEvent>> {
         if msg.message_type() != zbus::message::Type::Signal {
             bail!("Event message {:?} is not dbus signal", msg.message_type());
         }
@@ -75,63 +217,134 @@ impl LinkEvent {
             bail!("Invalid path: {:?}", &msg);
         };
 
-        let link = LinkEvent::link_from_path(&path, conn).await?;
-        debug!("Get link details of {link:?}");
-        let proxy = NetworkManagerProxy::new(conn).await?;
-        let describe_link = proxy.describe_link(link.index).await?;
-        let link_details = match serde_json::from_str::<LinkDetails>(&describe_link)
-            .with_context(|| format!("Cannot get link state of `{}`", link.name))
-        {
-            Ok(link_details) => {
-                debug!(
-                    "AdministrativeState: {a}\
-                    ,  OperationalState: {b}\
-                    ,  CarrierState: {c}\
-                    ,  AddressState: {d}\
-                    ,  IPv4AddressState: {e}\
-                    ,  IPv6AddressState: {f}",
-                    a = link_details
-                        .administrative_state
-                        .as_deref()
-                        .unwrap_or("unavailable"),
-                    b = link_details.operational_state,
-                    c = link_details.carrier_state,
-                    d = link_details.address_state,
-                    e = link_details.ipv4_address_state,
-                    f = link_details.ipv6_address_state
-                );
-                link_details
-            }
-            Err(err) => bail!("{err:#}"),
-        };
+        let index = ifindex_from_path(&path)?;
+
+        let (interface, changed, invalidated): (String, HashMap<String, OwnedValue>, Vec<String>) =
+            msg.body()
+                .deserialize()
+                .with_context(|| format!("Invalid PropertiesChanged signal of `{path}`"))?;
+
+        if interface != "org.freedesktop.network1.Link" {
+            bail!("{interface} is not 'org.freedesktop.network1.Link'");
+        }
+
+        let mut states = LinkStates::new();
+        for (property, value) in changed {
+            let Some(state_type) = StateType::from_property(&property) else {
+                continue;
+            };
+            let state = <&str>::try_from(&*value)
+                .with_context(|| format!("Invalid value of {property}: {value:?}"))?;
+            states.insert(state_type, state.to_string());
+        }
+
+        let invalidated = invalidated
+            .iter()
+            .any(|property| StateType::from_property(property).is_some());
 
         Ok(Box::new(LinkEvent {
-            iface: link.name,
-            state: link_details.operational_state.clone(),
-            path: msg.to_string(),
-            link_details,
-            link_details_json: describe_link,
+            index,
+            path,
+            serial: msg.primary_header().serial_num().get(),
+            states,
+            invalidated,
         }))
     }
+}
 
-    async fn link_from_path(path: &str, conn: &::zbus::Connection) -> Result<Link> {
-        let proxy = NetworkManagerProxy::new(conn).await?;
-        let links = proxy.list_links().await?;
-        for (index, name, p) in links {
-            if path == p.as_str() {
-                return Ok(Link {
-                    index,
-                    name,
-                    _path: p.to_string(),
-                });
-            }
+impl fmt::Display for LinkEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "link {} -->", self.index)?;
+        for (state_type, state) in &self.states {
+            write!(f, " {state_type}={state}")?;
+        }
+        if self.invalidated {
+            write!(f, " (invalidated)")?;
         }
-        bail!("No iface found on {path}");
+        Ok(())
     }
 }
 
-impl std::fmt::Display for LinkEvent {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{} --> {}", self.iface, self.state)
+/// Current name of a link in the kernel, none if the link is gone. It is cheap enough to be
+/// read on every link event, unlike `ListLinks`.
+pub fn kernel_name(index: i32) -> Result<Option<String>> {
+    let interfaces = if_nameindex().context("Failed to list network interfaces")?;
+    Ok(interfaces
+        .iter()
+        .find(|interface| i32::try_from(interface.index()) == Ok(index))
+        .map(|interface| interface.name().to_string_lossy().into_owned()))
+}
+
+/// Decode ifindex from an escaped link object path,
+/// e.g. `/org/freedesktop/network1/link/_33` is ifindex 3
+pub fn ifindex_from_path(path: &str) -> Result<i32> {
+    let Some(label) = path.strip_prefix("/org/freedesktop/network1/link/") else {
+        bail!("`{path}` is not a link object path");
+    };
+
+    // Object path label is escaped by sd-bus, every non-alphanumeric character and a leading
+    // digit are replaced by `_` followed by their two hex digits.
+    let mut index = String::new();
+    let mut chars = label.chars();
+    while let Some(c) = chars.next() {
+        if c == '_' {
+            let hex: String = chars.by_ref().take(2).collect();
+            let byte = u8::from_str_radix(&hex, 16)
+                .with_context(|| format!("Invalid escape sequence in `{path}`"))?;
+            index.push(char::from(byte));
+        } else {
+            index.push(c);
+        }
+    }
+
+    index
+        .parse::<i32>()
+        .with_context(|| format!("Invalid ifindex `{index}` in `{path}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ifindex_from_path() {
+        assert_eq!(
+            ifindex_from_path("/org/freedesktop/network1/link/_31").unwrap(),
+            1
+        );
+        assert_eq!(
+            ifindex_from_path("/org/freedesktop/network1/link/_33").unwrap(),
+            3
+        );
+        assert_eq!(
+            ifindex_from_path("/org/freedesktop/network1/link/_3128").unwrap(),
+            128
+        );
+        assert!(ifindex_from_path("/org/freedesktop/network1/link/_3").is_err());
+        assert!(ifindex_from_path("/org/freedesktop/network1/link/lo").is_err());
+        assert!(ifindex_from_path("/org/freedesktop/network1").is_err());
+    }
+
+    #[test]
+    fn test_kernel_name() {
+        let index = nix::net::if_::if_nametoindex("lo").unwrap();
+        assert_eq!(
+            kernel_name(i32::try_from(index).unwrap())
+                .unwrap()
+                .as_deref(),
+            Some("lo")
+        );
+        assert_eq!(kernel_name(i32::MAX).unwrap(), None);
+    }
+
+    #[test]
+    fn test_state_type_from_property() {
+        for state_type in StateType::ALL {
+            assert_eq!(
+                StateType::from_property(&state_type.to_string()),
+                Some(state_type)
+            );
+        }
+        assert_eq!(StateType::from_property("OnlineState"), None);
     }
 }
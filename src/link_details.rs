@@ -0,0 +1,770 @@
+//! # Link details which are described by `org.freedesktop.network1.Manager.DescribeLink`
+//!
+//! The JSON schema varies between systemd versions, keys are added in newer versions and some
+//! are missing in older ones. Every field is optional and is parsed leniently, an unknown key
+//! is ignored and a value of unexpected type is treated as missing. So a schema change of one
+//! field never prevents the link states from being parsed.
+
+use std::{
+    fmt,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+    },
+};
+
+use serde::{
+    Deserialize,
+    Deserializer,
+    Serialize,
+    Serializer,
+    de::DeserializeOwned,
+};
+
+use crate::link::{
+    LinkStates,
+    StateType,
+};
+
+/// Address family of `AF_INET`
+pub const AF_INET: i32 = 2;
+
+/// Address family of `AF_INET6`
+pub const AF_INET6: i32 = 10;
+
+/// Link state enum which falls back to `Unknown` for a state that is not known yet
+macro_rules! state_enum {
+    (
+        $(#[$meta:meta])*
+        $name:ident { $($variant:ident => $value:literal),+ $(,)? }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum $name {
+            $($variant,)+
+            Unknown(String),
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $($name::$variant => $value,)+
+                    $name::Unknown(value) => value,
+                }
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(value: &str) -> Self {
+                match value {
+                    $($value => $name::$variant,)+
+                    _ => $name::Unknown(value.to_string()),
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.as_str())
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(self.as_str())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let value = String::deserialize(deserializer)?;
+                Ok($name::from(value.as_str()))
+            }
+        }
+    };
+}
+
+state_enum! {
+    /// See `man networkctl`
+    OperationalState {
+        Missing => "missing",
+        Off => "off",
+        NoCarrier => "no-carrier",
+        Dormant => "dormant",
+        DegradedCarrier => "degraded-carrier",
+        Carrier => "carrier",
+        Degraded => "degraded",
+        Enslaved => "enslaved",
+        Routable => "routable",
+    }
+}
+
+state_enum! {
+    /// See `man networkctl`
+    CarrierState {
+        Off => "off",
+        NoCarrier => "no-carrier",
+        Dormant => "dormant",
+        DegradedCarrier => "degraded-carrier",
+        Carrier => "carrier",
+        Enslaved => "enslaved",
+    }
+}
+
+state_enum! {
+    /// State of `AddressState`, `IPv4AddressState` and `IPv6AddressState`
+    AddressState {
+        Off => "off",
+        Degraded => "degraded",
+        Routable => "routable",
+    }
+}
+
+state_enum! {
+    /// Setup state of a link, see `man networkctl`
+    AdministrativeState {
+        Pending => "pending",
+        Initialized => "initialized",
+        Configuring => "configuring",
+        Configured => "configured",
+        Unmanaged => "unmanaged",
+        Failed => "failed",
+        Linger => "linger",
+    }
+}
+
+state_enum! {
+    OnlineState {
+        Offline => "offline",
+        Partial => "partial",
+        Online => "online",
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LinkDetails {
+    #[serde(default, deserialize_with = "lenient")]
+    pub index: Option<i32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub name: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient_vec")]
+    pub alternative_names: Vec<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub master_interface_index: Option<i32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub kind: Option<String>,
+
+    #[serde(rename = "Type", default, deserialize_with = "lenient")]
+    pub link_type: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub driver: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub flags: Option<u32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub flags_string: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub kernel_operational_state_string: Option<String>,
+
+    #[serde(rename = "MTU", default, deserialize_with = "lenient")]
+    pub mtu: Option<u32>,
+
+    #[serde(rename = "MinimumMTU", default, deserialize_with = "lenient")]
+    pub minimum_mtu: Option<u32>,
+
+    #[serde(rename = "MaximumMTU", default, deserialize_with = "lenient")]
+    pub maximum_mtu: Option<u32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub hardware_address: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub permanent_hardware_address: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub broadcast_address: Option<Vec<u8>>,
+
+    #[serde(rename = "IPv6LinkLocalAddress", default, deserialize_with = "lenient")]
+    pub ipv6_link_local_address: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub wireless_lan_interface_type_string: Option<String>,
+
+    #[serde(rename = "SSID", default, deserialize_with = "lenient")]
+    pub ssid: Option<String>,
+
+    #[serde(rename = "BSSID", default, deserialize_with = "lenient")]
+    pub bssid: Option<Vec<u8>>,
+
+    // systemd 249's Manager.DescribeLink() JSON, as shipped in Ubuntu 22.04,
+    // does not include this key, even though AdministrativeState is available
+    // as a property on org.freedesktop.network1.Link. systemd 255's
+    // DescribeLink() JSON does include it, so keep parsing it when present.
+    #[serde(default, deserialize_with = "lenient")]
+    pub administrative_state: Option<AdministrativeState>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub operational_state: Option<OperationalState>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub carrier_state: Option<CarrierState>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub address_state: Option<AddressState>,
+
+    #[serde(rename = "IPv4AddressState", default, deserialize_with = "lenient")]
+    pub ipv4_address_state: Option<AddressState>,
+
+    #[serde(rename = "IPv6AddressState", default, deserialize_with = "lenient")]
+    pub ipv6_address_state: Option<AddressState>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub online_state: Option<OnlineState>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub required_for_online: Option<bool>,
+
+    #[serde(default, deserialize_with = "lenient_vec")]
+    pub required_operational_state_for_online: Vec<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub required_family_for_online: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub activation_policy: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub network_file: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient_vec")]
+    pub network_file_dropins: Vec<String>,
+
+    #[serde(rename = "NetDevFile", default, deserialize_with = "lenient")]
+    pub netdev_file: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub link_file: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub path: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub vendor: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub model: Option<String>,
+
+    #[serde(rename = "DNS", default, deserialize_with = "lenient_vec")]
+    pub dns: Vec<DnsServer>,
+
+    #[serde(rename = "NTP", default, deserialize_with = "lenient_vec")]
+    pub ntp: Vec<NtpServer>,
+
+    #[serde(default, deserialize_with = "lenient_vec")]
+    pub search_domains: Vec<Domain>,
+
+    #[serde(default, deserialize_with = "lenient_vec")]
+    pub route_domains: Vec<Domain>,
+
+    #[serde(rename = "DNSSEC", default, deserialize_with = "lenient")]
+    pub dnssec: Option<String>,
+
+    #[serde(rename = "DNSOverTLS", default, deserialize_with = "lenient")]
+    pub dns_over_tls: Option<String>,
+
+    #[serde(rename = "LLMNR", default, deserialize_with = "lenient")]
+    pub llmnr: Option<String>,
+
+    #[serde(rename = "MulticastDNS", default, deserialize_with = "lenient")]
+    pub multicast_dns: Option<String>,
+
+    #[serde(rename = "DNSDefaultRoute", default, deserialize_with = "lenient")]
+    pub dns_default_route: Option<bool>,
+
+    #[serde(default, deserialize_with = "lenient_vec")]
+    pub addresses: Vec<Address>,
+
+    #[serde(default, deserialize_with = "lenient_vec")]
+    pub routes: Vec<Route>,
+
+    #[serde(rename = "DHCPv4Client", default, deserialize_with = "lenient")]
+    pub dhcpv4_client: Option<Dhcpv4Client>,
+
+    #[serde(rename = "DHCPv6Client", default, deserialize_with = "lenient")]
+    pub dhcpv6_client: Option<Dhcpv6Client>,
+
+    #[serde(rename = "LLDP", default, deserialize_with = "lenient_vec")]
+    pub lldp: Vec<LldpNeighbor>,
+}
+
+impl LinkDetails {
+    pub fn state(&self, state_type: StateType) -> Option<String> {
+        match state_type {
+            StateType::Operational => self.operational_state.as_ref().map(|s| s.to_string()),
+            StateType::Carrier => self.carrier_state.as_ref().map(|s| s.to_string()),
+            StateType::Address => self.address_state.as_ref().map(|s| s.to_string()),
+            StateType::Ipv4Address => self.ipv4_address_state.as_ref().map(|s| s.to_string()),
+            StateType::Ipv6Address => self.ipv6_address_state.as_ref().map(|s| s.to_string()),
+            StateType::Administrative => self.administrative_state.as_ref().map(|s| s.to_string()),
+        }
+    }
+
+    /// Collect all available states of a link
+    pub fn states(&self) -> LinkStates {
+        StateType::ALL
+            .iter()
+            .filter_map(|state_type| self.state(*state_type).map(|state| (*state_type, state)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Address {
+    #[serde(default, deserialize_with = "lenient")]
+    pub family: Option<i32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub address: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub peer: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub prefix_length: Option<u8>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub scope: Option<u32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub scope_string: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub flags_string: Option<String>,
+
+    #[serde(
+        rename = "PreferredLifetimeUSec",
+        default,
+        deserialize_with = "lenient"
+    )]
+    pub preferred_lifetime_usec: Option<u64>,
+
+    #[serde(rename = "ValidLifetimeUSec", default, deserialize_with = "lenient")]
+    pub valid_lifetime_usec: Option<u64>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub config_source: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub config_state: Option<String>,
+}
+
+impl Address {
+    pub fn ip(&self) -> Option<IpAddr> {
+        ip_from_bytes(self.family, self.address.as_deref()?)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Route {
+    #[serde(default, deserialize_with = "lenient")]
+    pub family: Option<i32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub destination: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub destination_prefix_length: Option<u8>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub gateway: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub preferred_source: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub scope_string: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub protocol_string: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub type_string: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub priority: Option<u32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub table: Option<u32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub config_source: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub config_state: Option<String>,
+}
+
+impl Route {
+    pub fn gateway(&self) -> Option<IpAddr> {
+        ip_from_bytes(self.family, self.gateway.as_deref()?)
+    }
+
+    /// A route to any destination, i.e. `0.0.0.0/0` or `::/0`
+    pub fn is_default(&self) -> bool {
+        self.destination_prefix_length == Some(0)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DnsServer {
+    #[serde(default, deserialize_with = "lenient")]
+    pub family: Option<i32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub address: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub port: Option<u16>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub server_name: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub config_source: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub config_provider: Option<Vec<u8>>,
+}
+
+impl DnsServer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        ip_from_bytes(self.family, self.address.as_deref()?)
+    }
+}
+
+/// NTP server is either an address or a host name
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NtpServer {
+    #[serde(default, deserialize_with = "lenient")]
+    pub family: Option<i32>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub address: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub server: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub config_source: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Domain {
+    #[serde(default, deserialize_with = "lenient")]
+    pub domain: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub config_source: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Dhcpv4Client {
+    #[serde(default, deserialize_with = "lenient")]
+    pub lease: Option<DhcpLease>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DhcpLease {
+    #[serde(rename = "LeaseTimestampUSec", default, deserialize_with = "lenient")]
+    pub lease_timestamp_usec: Option<u64>,
+
+    #[serde(rename = "Timeout1USec", default, deserialize_with = "lenient")]
+    pub timeout1_usec: Option<u64>,
+
+    #[serde(rename = "Timeout2USec", default, deserialize_with = "lenient")]
+    pub timeout2_usec: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Dhcpv6Client {
+    #[serde(default, deserialize_with = "lenient")]
+    pub lease: Option<DhcpLease>,
+
+    #[serde(default, deserialize_with = "lenient_vec")]
+    pub prefixes: Vec<Dhcpv6Prefix>,
+
+    #[serde(rename = "DUID", default, deserialize_with = "lenient")]
+    pub duid: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Dhcpv6Prefix {
+    #[serde(default, deserialize_with = "lenient")]
+    pub prefix: Option<Vec<u8>>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub prefix_length: Option<u8>,
+
+    #[serde(
+        rename = "PreferredLifetimeUSec",
+        default,
+        deserialize_with = "lenient"
+    )]
+    pub preferred_lifetime_usec: Option<u64>,
+
+    #[serde(rename = "ValidLifetimeUSec", default, deserialize_with = "lenient")]
+    pub valid_lifetime_usec: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LldpNeighbor {
+    #[serde(rename = "ChassisID", default, deserialize_with = "lenient")]
+    pub chassis_id: Option<String>,
+
+    #[serde(rename = "PortID", default, deserialize_with = "lenient")]
+    pub port_id: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub port_description: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub system_name: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub system_description: Option<String>,
+
+    #[serde(default, deserialize_with = "lenient")]
+    pub enabled_capabilities: Option<u16>,
+
+    #[serde(rename = "VlanID", default, deserialize_with = "lenient")]
+    pub vlan_id: Option<u16>,
+}
+
+/// Convert address bytes of `AF_INET` or `AF_INET6` to IP address
+///
+/// The bytes must match the length of the family. Without a family, which is missing in some
+/// systemd versions, the family is told by the length.
+pub fn ip_from_bytes(family: Option<i32>, bytes: &[u8]) -> Option<IpAddr> {
+    match family {
+        Some(AF_INET) => <[u8; 4]>::try_from(bytes)
+            .ok()
+            .map(|octets| IpAddr::V4(Ipv4Addr::from(octets))),
+        Some(AF_INET6) => <[u8; 16]>::try_from(bytes)
+            .ok()
+            .map(|octets| IpAddr::V6(Ipv6Addr::from(octets))),
+        Some(_) => None,
+        None => {
+            ip_from_bytes(Some(AF_INET), bytes).or_else(|| ip_from_bytes(Some(AF_INET6), bytes))
+        }
+    }
+}
+
+/// Deserialize an optional value, a value of unexpected type is treated as missing
+fn lenient<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).ok())
+}
+
+/// Deserialize a list, an element of unexpected type is skipped
+fn lenient_vec<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    let serde_json::Value::Array(values) = value else {
+        return Ok(Vec::new());
+    };
+    Ok(values
+        .into_iter()
+        .filter_map(|value| serde_json::from_value(value).ok())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! fixture {
+        ($name:literal) => {
+            serde_json::from_str::<LinkDetails>(include_str!(concat!(
+                "../tests/fixtures/describe-link/",
+                $name
+            )))
+            .unwrap()
+        };
+    }
+
+    #[test]
+    fn parse_systemd_249() {
+        let link_details = fixture!("systemd-249-ether.json");
+        assert_eq!(link_details.index, Some(2));
+        assert_eq!(link_details.name.as_deref(), Some("enp3s0"));
+        assert_eq!(link_details.link_type.as_deref(), Some("ether"));
+        assert_eq!(link_details.administrative_state, None);
+        assert_eq!(
+            link_details.operational_state,
+            Some(OperationalState::Routable)
+        );
+        assert_eq!(link_details.carrier_state, Some(CarrierState::Carrier));
+        assert_eq!(
+            link_details.ipv6_address_state,
+            Some(AddressState::Degraded)
+        );
+        assert_eq!(link_details.online_state, Some(OnlineState::Online));
+        assert_eq!(
+            link_details.network_file.as_deref(),
+            Some("/etc/systemd/network/20-wired.network")
+        );
+
+        let states = link_details.states();
+        assert_eq!(states.len(), 5);
+        assert!(!states.contains_key(&StateType::Administrative));
+    }
+
+    #[test]
+    fn parse_systemd_252() {
+        let link_details = fixture!("systemd-252-wlan.json");
+        assert_eq!(link_details.name.as_deref(), Some("wlp3s0"));
+        assert_eq!(link_details.link_type.as_deref(), Some("wlan"));
+        assert_eq!(link_details.driver.as_deref(), Some("iwlwifi"));
+        assert_eq!(link_details.ssid.as_deref(), Some("HomeNetwork"));
+        assert_eq!(
+            link_details.bssid,
+            Some(vec![0x0c, 0x80, 0x63, 0x1a, 0x2b, 0x3c])
+        );
+        assert_eq!(
+            link_details.administrative_state,
+            Some(AdministrativeState::Configured)
+        );
+        assert_eq!(link_details.states().len(), 6);
+
+        assert_eq!(link_details.addresses.len(), 2);
+        assert_eq!(
+            link_details.addresses[0].ip(),
+            Some("192.168.1.101".parse().unwrap())
+        );
+        assert_eq!(link_details.addresses[0].prefix_length, Some(24));
+        assert_eq!(
+            link_details.addresses[1].ip(),
+            Some("fe80::9a3b:8fff:fe41:7a02".parse().unwrap())
+        );
+
+        let gateway = link_details
+            .routes
+            .iter()
+            .find(|route| route.is_default())
+            .and_then(|route| route.gateway());
+        assert_eq!(gateway, Some("192.168.1.1".parse().unwrap()));
+
+        assert_eq!(link_details.dns.len(), 1);
+        assert_eq!(
+            link_details.dns[0].ip(),
+            Some("192.168.1.1".parse().unwrap())
+        );
+        assert!(
+            link_details
+                .dhcpv4_client
+                .and_then(|client| client.lease)
+                .is_some_and(|lease| lease.timeout1_usec == Some(1800000000))
+        );
+    }
+
+    #[test]
+    fn parse_systemd_255() {
+        let link_details = fixture!("systemd-255-ether.json");
+        assert_eq!(link_details.alternative_names, vec!["enx0242ac110002"]);
+        assert_eq!(link_details.kind, None);
+        assert_eq!(link_details.mtu, Some(1500));
+        assert_eq!(
+            link_details.administrative_state,
+            Some(AdministrativeState::Configured)
+        );
+        assert_eq!(link_details.network_file_dropins.len(), 1);
+        assert_eq!(link_details.ntp.len(), 1);
+        assert_eq!(
+            link_details.ntp[0].server.as_deref(),
+            Some("time.example.com")
+        );
+        assert_eq!(link_details.search_domains.len(), 1);
+        assert_eq!(link_details.lldp.len(), 1);
+        assert_eq!(
+            link_details.lldp[0].system_name.as_deref(),
+            Some("switch01")
+        );
+
+        let dhcpv6_client = link_details.dhcpv6_client.unwrap();
+        assert_eq!(dhcpv6_client.prefixes.len(), 1);
+        assert_eq!(dhcpv6_client.prefixes[0].prefix_length, Some(56));
+    }
+
+    #[test]
+    fn parse_unknown_and_malformed_values() {
+        let link_details = serde_json::from_str::<LinkDetails>(
+            r#"{
+                "OperationalState": "some-future-state",
+                "CarrierState": "carrier",
+                "MTU": "not a number",
+                "Addresses": [ { "Family": 2, "Address": [10, 0, 0, 1] }, 42 ],
+                "SomeFutureKey": { "Nested": true }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            link_details.operational_state,
+            Some(OperationalState::Unknown("some-future-state".to_string()))
+        );
+        assert_eq!(
+            link_details.state(StateType::Operational).as_deref(),
+            Some("some-future-state")
+        );
+        assert_eq!(link_details.carrier_state, Some(CarrierState::Carrier));
+        assert_eq!(link_details.mtu, None);
+        assert_eq!(link_details.addresses.len(), 1);
+        assert_eq!(
+            link_details.addresses[0].ip(),
+            Some("10.0.0.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ip_from_bytes_of_family() {
+        let v4 = [10, 0, 0, 1];
+        let v6 = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert_eq!(
+            ip_from_bytes(Some(AF_INET), &v4),
+            Some("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            ip_from_bytes(Some(AF_INET6), &v6),
+            Some("fe80::1".parse().unwrap())
+        );
+        assert_eq!(ip_from_bytes(Some(AF_INET), &v6), None);
+        assert_eq!(ip_from_bytes(Some(AF_INET6), &v4), None);
+        assert_eq!(ip_from_bytes(Some(7), &v4), None);
+        assert_eq!(ip_from_bytes(None, &v6), Some("fe80::1".parse().unwrap()));
+        assert_eq!(ip_from_bytes(None, &[1, 2, 3]), None);
+    }
+}
@@ -1,5 +1,10 @@
 use std::{
+    fs,
     io,
+    path::{
+        Path,
+        PathBuf,
+    },
     process::ExitCode,
 };
 
@@ -11,8 +16,24 @@ use anyhow::{
 use clap::Parser;
 use mimalloc::MiMalloc;
 use networkd_broker::{
-    args::Arguments,
-    broker::Broker,
+    allowlist::Allowlist,
+    args::{
+        Arguments,
+        Command,
+    },
+    broker::{
+        Broker,
+        BrokerOptions,
+    },
+    config::Config,
+    journal::{
+        HideJournalFields,
+        JournalLayer,
+    },
+    launcher::Launcher,
+    script_config::ScriptConfig,
+    script_index::ScriptIndex,
+    settle::SettleTimes,
 };
 use tracing::{
     debug,
@@ -20,7 +41,11 @@ use tracing::{
     info,
     warn,
 };
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{
+    EnvFilter,
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -28,10 +53,17 @@ static GLOBAL: MiMalloc = MiMalloc;
 fn run() -> Result<()> {
     let filter =
         EnvFilter::try_from_default_env().unwrap_or(EnvFilter::try_new("networkd_broker=info")?);
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .without_time()
-        .with_writer(io::stderr)
+    // Log to journal directly when stderr is connected to it, e.g. run as a systemd service
+    let journal = libsystemd::logging::connected_to_journal();
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(journal.then_some(JournalLayer))
+        .with((!journal).then(|| {
+            tracing_subscriber::fmt::layer()
+                .without_time()
+                .fmt_fields(HideJournalFields)
+                .with_writer(io::stderr)
+        }))
         .try_init()
         .map_err(|err| anyhow!("{err:#}"))
         .context("Failed to initialize tracing subscriber")?;
@@ -39,8 +71,35 @@ fn run() -> Result<()> {
     let arguments = Arguments::parse();
     debug!("Run with {:?}", arguments);
 
+    if let Some(Command::GenerateAllowlist { output }) = &arguments.command {
+        return generate_allowlist(&arguments, output.as_deref());
+    }
+
+    let allowlist = arguments
+        .allowlist
+        .as_deref()
+        .map(Allowlist::load)
+        .transpose()?;
+    let config = Config::load(&arguments.config, allowlist.as_ref())?;
+
     zbus::block_on(async {
-        let mut broker = Broker::new(arguments.script_dir, arguments.timeout)
+        let options = BrokerOptions {
+            script_root_dirs: arguments.script_root_dirs(),
+            script_timeout: arguments.timeout,
+            script_grace: arguments.grace,
+            script_output: arguments.script_output,
+            compat: arguments.compat,
+            settle: SettleTimes::new(&arguments.settle),
+            flap_detection: arguments.flap_detection(),
+            allowed_owners: arguments.allowed_owners()?,
+            config_file: arguments.config.clone(),
+            hooks: config.hooks,
+            allowlist_file: arguments.allowlist.clone(),
+            allowlist,
+        };
+        let launcher = Launcher::new(arguments.max_concurrency, arguments.supersede)
+            .context("Failed to start script launcher")?;
+        let mut broker = Broker::new(options, launcher)
             .await
             .context("Failed to create broker thread")?;
 
@@ -55,6 +114,12 @@ fn run() -> Result<()> {
             {
                 warn!("{err:#}");
             }
+        } else if let Err(err) = broker
+            .replay()
+            .await
+            .context("Failed to replay link changes since the last run")
+        {
+            warn!("{err:#}");
         }
 
         broker
@@ -64,6 +129,45 @@ fn run() -> Result<()> {
     })
 }
 
+/// Write an allowlist of the scripts which would run now, of the script roots and hooks, and of
+/// the configuration file
+fn generate_allowlist(arguments: &Arguments, output: Option<&Path>) -> Result<()> {
+    let config = Config::load(&arguments.config, None)?;
+    let index = ScriptIndex::new(
+        arguments.script_root_dirs(),
+        None,
+        None,
+        arguments.allowed_owners()?,
+        None,
+    );
+
+    let mut paths = index
+        .all_scripts()
+        .map(|script| script.path().to_path_buf())
+        .collect::<Vec<_>>();
+    // Settings of scripts are approved with them
+    let sidecars = paths
+        .iter()
+        .map(|script| ScriptConfig::sidecar_path(script))
+        .filter(|sidecar| sidecar.exists())
+        .collect::<Vec<_>>();
+    paths.extend(sidecars);
+    paths.extend(config.scripts().map(Path::to_path_buf));
+    if arguments.config.exists() {
+        paths.push(arguments.config.clone());
+    }
+    paths.sort();
+    paths.dedup();
+
+    let allowlist = Allowlist::generate(paths.iter().map(PathBuf::as_path))?;
+    match output {
+        Some(output) => fs::write(output, allowlist)
+            .with_context(|| format!("Failed to write allowlist `{}`", output.display()))?,
+        None => print!("{allowlist}"),
+    }
+    Ok(())
+}
+
 fn main() -> ExitCode {
     if let Err(err) = run() {
         error!("{err:#}");
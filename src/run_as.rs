@@ -0,0 +1,145 @@
+//! # Unprivileged scripts
+//!
+//! A script runs as root unless it declares `user=` or `group=` in its settings, or it is owned
+//! by an allowed user, see `--allowed-owner`. Privileges are dropped in the child process
+//! before the script is executed.
+
+use std::{
+    ffi::CString,
+    io,
+    path::PathBuf,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use nix::unistd::{
+    Gid,
+    Group,
+    Uid,
+    User,
+    getgrouplist,
+    setgid,
+    setgroups,
+    setuid,
+};
+
+/// User and groups which a script runs as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunAs {
+    pub user: String,
+    pub uid: Uid,
+    pub gid: Gid,
+
+    /// Supplementary groups
+    pub groups: Vec<Gid>,
+
+    pub home: PathBuf,
+}
+
+impl RunAs {
+    /// User and group which are declared by script settings, `None` if neither is declared.
+    /// Without `user`, the script runs as root with `group`. Without `group`, it runs with the
+    /// primary group of `user`.
+    pub fn from_settings(user: Option<&str>, group: Option<&str>) -> Result<Option<RunAs>> {
+        if user.is_none() && group.is_none() {
+            return Ok(None);
+        }
+
+        let user = match user {
+            Some(user) => find_user(user)?,
+            None => find_user("0")?,
+        };
+        let gid = match group {
+            Some(group) => find_group(group)?.gid,
+            None => user.gid,
+        };
+        RunAs::new(user, gid).map(Some)
+    }
+
+    /// Owner of a script with its primary group
+    pub fn owner(uid: u32) -> Result<RunAs> {
+        let user = find_user(&uid.to_string())?;
+        let gid = user.gid;
+        RunAs::new(user, gid)
+    }
+
+    fn new(user: User, gid: Gid) -> Result<RunAs> {
+        let name = CString::new(user.name.as_str())
+            .with_context(|| format!("Invalid user name `{}`", user.name))?;
+        let groups = getgrouplist(&name, gid)
+            .with_context(|| format!("Failed to get groups of user `{}`", user.name))?;
+        Ok(RunAs {
+            user: user.name,
+            uid: user.uid,
+            gid,
+            groups,
+            home: user.dir,
+        })
+    }
+
+    /// Whether it is the user and group of the broker itself, nothing to drop
+    pub fn is_current(&self) -> bool {
+        self.uid == Uid::effective() && self.gid == Gid::effective()
+    }
+
+    /// Switch the current process to the user and groups. It is called in the child process
+    /// between fork and exec, so it only makes system calls.
+    pub fn drop_privileges(&self) -> io::Result<()> {
+        setgroups(&self.groups)?;
+        setgid(self.gid)?;
+        setuid(self.uid)?;
+        Ok(())
+    }
+}
+
+/// Find user by name or uid
+pub(crate) fn find_user(user: &str) -> Result<User> {
+    let found = match user.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(user),
+    };
+    match found.with_context(|| format!("Failed to look up user `{user}`"))? {
+        Some(user) => Ok(user),
+        None => bail!("Unknown user `{user}`"),
+    }
+}
+
+/// Find group by name or gid
+fn find_group(group: &str) -> Result<Group> {
+    let found = match group.parse::<u32>() {
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+        Err(_) => Group::from_name(group),
+    };
+    match found.with_context(|| format!("Failed to look up group `{group}`"))? {
+        Some(group) => Ok(group),
+        None => bail!("Unknown group `{group}`"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_as() {
+        assert_eq!(RunAs::from_settings(None, None).unwrap(), None);
+
+        let root = RunAs::from_settings(Some("root"), None).unwrap().unwrap();
+        assert_eq!(root.uid, Uid::from_raw(0));
+        assert_eq!(root.gid, Gid::from_raw(0));
+        assert_eq!(RunAs::owner(0).unwrap(), root);
+
+        let nobody = RunAs::from_settings(Some("65534"), Some("0"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(nobody.uid, Uid::from_raw(65534));
+        assert_eq!(nobody.gid, Gid::from_raw(0));
+        assert!(nobody.groups.contains(&Gid::from_raw(0)));
+
+        assert!(RunAs::from_settings(Some("no-such-user"), None).is_err());
+        assert!(RunAs::from_settings(None, Some("no-such-group")).is_err());
+    }
+}
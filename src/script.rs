@@ -1,21 +1,52 @@
 use std::{
     collections::HashMap,
+    ffi::OsStr,
     fmt,
-    os::unix::fs::MetadataExt,
+    fs,
+    os::unix::{
+        fs::MetadataExt,
+        process::{
+            CommandExt,
+            ExitStatusExt,
+        },
+    },
     path::{
         Path,
         PathBuf,
     },
-    process::Command,
+    process::{
+        Child,
+        Command,
+        ExitStatus,
+    },
+    sync::{
+        Arc,
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
+    },
     thread,
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use anyhow::{
     Context,
     Result,
+    anyhow,
     bail,
 };
+use nix::{
+    sys::signal::{
+        Signal,
+        killpg,
+    },
+    unistd::Pid,
+};
 use tracing::{
     debug,
     info,
@@ -24,33 +55,185 @@ use tracing::{
 use wait_timeout::ChildExt;
 use walkdir::WalkDir;
 
+use crate::{
+    allowlist::{
+        Allowlist,
+        audit_refused,
+        sha256_file,
+    },
+    link::StateType,
+    link_details::LinkDetails,
+    run_as::RunAs,
+    script_config::{
+        SIDECAR_EXTENSION,
+        ScriptConfig,
+    },
+    script_output::{
+        OutputCapture,
+        OutputMode,
+        OutputSource,
+    },
+};
+
 pub const DEFAULT_TIMEOUT: u64 = 20; // seconds
+pub const DEFAULT_KILL_SIGNAL: Signal = Signal::SIGTERM;
+pub const DEFAULT_GRACE: u64 = 5; // seconds
 
-#[derive(Debug)]
+/// How often a terminated process group is checked during the grace period
+const TERMINATE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a running script is checked for cancellation
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A script which is a symlink to this is masked
+const MASK_TARGET: &str = "/dev/null";
+
+/// Suffixes of editor backups and leftovers of package managers, which are not scripts
+const IGNORED_SUFFIXES: [&str; 8] = [
+    "~", ".pacnew", ".pacsave", ".pacorig", ".rpmnew", ".rpmsave", ".rpmorig", ".swp",
+];
+
+/// Writable by group or others
+const UNSAFE_WRITE_BITS: u32 = 0o022;
+
+const STICKY_BIT: u32 = 0o1000;
+
+/// Shell which runs an inline command
+const SHELL: &str = "/bin/sh";
+
+/// Environment variables of the broker which are passed to an unprivileged script
+const PASSED_ENV_VARS: [&str; 3] = ["PATH", "LANG", "TZ"];
+
+static NEXT_EVENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Get a new identifier of a network event, which is logged with every script run in response
+/// to it
+pub fn next_event_id() -> u64 {
+    NEXT_EVENT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone)]
 pub enum EnvVar {
     DeviceIface(String),
+    PreviousIface(String),
     BrokerAction(String),
+    PreviousState(String),
+    StateType(String),
     Json(String),
+    OperationalState(String),
+    CarrierState(String),
+    AddressState(String),
+    Ipv4AddressState(String),
+    Ipv6AddressState(String),
+    AdministrativeState(String),
+    Ifindex(String),
+    Ipv4Addrs(String),
+    Ipv6Addrs(String),
+    Ssid(String),
+    Gateway(String),
+    FlapCount(String),
 
     #[allow(dead_code)]
     Custom {
         key: String,
         value: String,
     },
+
+    /// Variable without `NWD_` prefix, for scripts of other dispatchers
+    Unprefixed {
+        key: String,
+        value: String,
+    },
 }
 
 impl fmt::Display for EnvVar {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             EnvVar::DeviceIface(_) => write!(f, "NWD_DEVICE_IFACE"),
+            EnvVar::PreviousIface(_) => write!(f, "NWD_PREVIOUS_IFACE"),
             EnvVar::BrokerAction(_) => write!(f, "NWD_BROKER_ACTION"),
+            EnvVar::PreviousState(_) => write!(f, "NWD_PREVIOUS_STATE"),
+            EnvVar::StateType(_) => write!(f, "NWD_STATE_TYPE"),
             EnvVar::Json(_) => write!(f, "NWD_JSON"),
+            EnvVar::OperationalState(_) => write!(f, "NWD_OPERATIONAL_STATE"),
+            EnvVar::CarrierState(_) => write!(f, "NWD_CARRIER_STATE"),
+            EnvVar::AddressState(_) => write!(f, "NWD_ADDRESS_STATE"),
+            EnvVar::Ipv4AddressState(_) => write!(f, "NWD_IPV4_ADDRESS_STATE"),
+            EnvVar::Ipv6AddressState(_) => write!(f, "NWD_IPV6_ADDRESS_STATE"),
+            EnvVar::AdministrativeState(_) => write!(f, "NWD_ADMINISTRATIVE_STATE"),
+            EnvVar::Ifindex(_) => write!(f, "NWD_IFINDEX"),
+            EnvVar::Ipv4Addrs(_) => write!(f, "NWD_IPV4_ADDRS"),
+            EnvVar::Ipv6Addrs(_) => write!(f, "NWD_IPV6_ADDRS"),
+            EnvVar::Ssid(_) => write!(f, "NWD_SSID"),
+            EnvVar::Gateway(_) => write!(f, "NWD_GATEWAY"),
+            EnvVar::FlapCount(_) => write!(f, "NWD_FLAP_COUNT"),
             EnvVar::Custom { key, value: _ } => write!(f, "NWD_{key}"),
+            EnvVar::Unprefixed { key, value: _ } => write!(f, "{key}"),
         }
     }
 }
 
-#[derive(Debug)]
+impl EnvVar {
+    /// Environment variables of each parsed field of link details, a field which is not
+    /// available is left out
+    pub fn from_link_details(index: i32, link_details: &LinkDetails) -> Vec<EnvVar> {
+        let mut env_vars = vec![EnvVar::Ifindex(index.to_string())];
+
+        for state_type in StateType::ALL {
+            let Some(state) = link_details.state(state_type) else {
+                continue;
+            };
+            env_vars.push(match state_type {
+                StateType::Operational => EnvVar::OperationalState(state),
+                StateType::Carrier => EnvVar::CarrierState(state),
+                StateType::Address => EnvVar::AddressState(state),
+                StateType::Ipv4Address => EnvVar::Ipv4AddressState(state),
+                StateType::Ipv6Address => EnvVar::Ipv6AddressState(state),
+                StateType::Administrative => EnvVar::AdministrativeState(state),
+            });
+        }
+
+        let ips = link_details
+            .addresses
+            .iter()
+            .filter_map(|address| address.ip())
+            .collect::<Vec<_>>();
+        let join = |ips: Vec<String>| Some(ips.join(" ")).filter(|ips| !ips.is_empty());
+        if let Some(ipv4_addrs) = join(
+            ips.iter()
+                .filter(|ip| ip.is_ipv4())
+                .map(|ip| ip.to_string())
+                .collect(),
+        ) {
+            env_vars.push(EnvVar::Ipv4Addrs(ipv4_addrs));
+        }
+        if let Some(ipv6_addrs) = join(
+            ips.iter()
+                .filter(|ip| ip.is_ipv6())
+                .map(|ip| ip.to_string())
+                .collect(),
+        ) {
+            env_vars.push(EnvVar::Ipv6Addrs(ipv6_addrs));
+        }
+
+        if let Some(ssid) = &link_details.ssid {
+            env_vars.push(EnvVar::Ssid(ssid.clone()));
+        }
+
+        if let Some(gateway) = link_details
+            .routes
+            .iter()
+            .filter(|route| route.is_default())
+            .find_map(|route| route.gateway())
+        {
+            env_vars.push(EnvVar::Gateway(gateway.to_string()));
+        }
+
+        env_vars
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ScriptBuilder {
     path: PathBuf,
 
@@ -60,12 +243,39 @@ pub struct ScriptBuilder {
     /// iface
     arg1: String,
 
+    /// previous state
+    arg2: Option<String>,
+
     envs: HashMap<String, String>,
 
     default_timeout: u64,
+
+    default_grace: u64,
+
+    output_mode: OutputMode,
+
+    event_id: Option<u64>,
+
+    ifindex: Option<i32>,
+
+    /// User and groups which the script runs as, root if it is not set
+    run_as: Option<RunAs>,
+
+    /// Settings which are declared by the script itself
+    config: ScriptConfig,
+
+    /// Shell command which is run instead of `path`, `path` only names it
+    inline: Option<String>,
+
+    /// SHA-256 digest of the script in the allowlist
+    digest: Option<String>,
 }
 
 impl ScriptBuilder {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn set_path(mut self, path: &Path) -> Self {
         self.path = path.to_path_buf();
         self
@@ -81,12 +291,33 @@ impl ScriptBuilder {
         self
     }
 
+    pub fn set_arg2(mut self, previous_state: &str) -> Self {
+        self.arg2 = Some(previous_state.to_string());
+        self
+    }
+
     pub fn add_env(mut self, env_var: EnvVar) -> Self {
         let value = match &env_var {
             EnvVar::DeviceIface(value)
+            | EnvVar::PreviousIface(value)
             | EnvVar::BrokerAction(value)
+            | EnvVar::PreviousState(value)
+            | EnvVar::StateType(value)
             | EnvVar::Json(value)
-            | EnvVar::Custom { key: _, value } => value,
+            | EnvVar::OperationalState(value)
+            | EnvVar::CarrierState(value)
+            | EnvVar::AddressState(value)
+            | EnvVar::Ipv4AddressState(value)
+            | EnvVar::Ipv6AddressState(value)
+            | EnvVar::AdministrativeState(value)
+            | EnvVar::Ifindex(value)
+            | EnvVar::Ipv4Addrs(value)
+            | EnvVar::Ipv6Addrs(value)
+            | EnvVar::Ssid(value)
+            | EnvVar::Gateway(value)
+            | EnvVar::FlapCount(value)
+            | EnvVar::Custom { key: _, value }
+            | EnvVar::Unprefixed { key: _, value } => value,
         };
 
         self.envs.insert(env_var.to_string(), value.to_string());
@@ -98,18 +329,74 @@ impl ScriptBuilder {
         self
     }
 
+    pub fn set_default_grace(mut self, grace: u64) -> Self {
+        self.default_grace = grace;
+        self
+    }
+
+    pub fn set_output_mode(mut self, output_mode: OutputMode) -> Self {
+        self.output_mode = output_mode;
+        self
+    }
+
+    pub fn set_event_id(mut self, event_id: u64) -> Self {
+        self.event_id = Some(event_id);
+        self
+    }
+
+    pub fn set_ifindex(mut self, ifindex: i32) -> Self {
+        self.ifindex = Some(ifindex);
+        self
+    }
+
+    pub fn set_config(mut self, config: ScriptConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn set_run_as(mut self, run_as: RunAs) -> Self {
+        self.run_as = Some(run_as);
+        self
+    }
+
+    pub fn set_inline(mut self, command: &str) -> Self {
+        self.inline = Some(command.to_string());
+        self
+    }
+
+    pub fn set_digest(mut self, digest: &str) -> Self {
+        self.digest = Some(digest.to_string());
+        self
+    }
+
     pub fn build(self) -> Script {
-        let timeout = if ScriptBuilder::should_run_nowait(&self.path) {
+        let nowait = self
+            .config
+            .nowait
+            .unwrap_or_else(|| ScriptBuilder::should_run_nowait(&self.path));
+        let timeout = if nowait {
             None
         } else {
-            Some(self.default_timeout)
+            Some(self.config.timeout.unwrap_or(self.default_timeout))
         };
 
+        let mut args = vec![self.arg0, self.arg1];
+        args.extend(self.arg2);
+
         Script {
             path: self.path,
-            args: vec![self.arg0, self.arg1],
+            args,
             envs: self.envs,
             timeout,
+            kill_signal: self.config.kill_signal.unwrap_or(DEFAULT_KILL_SIGNAL),
+            grace: self.config.grace.unwrap_or(self.default_grace),
+            output_mode: self.output_mode,
+            event_id: self.event_id.unwrap_or_else(next_event_id),
+            ifindex: self.ifindex.unwrap_or_default(),
+            cancel: CancelToken::default(),
+            run_as: self.run_as.filter(|run_as| !run_as.is_current()),
+            inline: self.inline,
+            digest: self.digest,
         }
     }
 
@@ -117,11 +404,15 @@ impl ScriptBuilder {
     ///
     /// * `uid` - Acceptable user ID of a script. Default is 0 (root)
     /// * `gid` - Acceptable group ID of a script. Default is 0 (root)
+    /// * `allowed_owners` - Other user IDs whose scripts are accepted, they run as their owners
+    /// * `allowlist` - Approved digests of scripts, any script is accepted without it
     ///
     pub fn build_from(
         path: &Path,
         uid: Option<u32>,
         gid: Option<u32>,
+        allowed_owners: &[u32],
+        allowlist: Option<&Allowlist>,
     ) -> Result<Vec<ScriptBuilder>> {
         let mut scripts: Vec<ScriptBuilder> = Vec::new();
 
@@ -133,6 +424,12 @@ impl ScriptBuilder {
         let uid = uid.unwrap_or(0); // Default is UID of root
         let gid = gid.unwrap_or(0); // Default is GID of root
 
+        // Anyone who can write to the directory, or any directory above it, can replace scripts
+        if let Err(err) = check_dir_trusted(path, uid) {
+            warn!("Ignore scripts in `{}`. {err:#}", path.display());
+            return Ok(scripts);
+        }
+
         for entry in WalkDir::new(path)
             .min_depth(1)
             .max_depth(1)
@@ -141,6 +438,16 @@ impl ScriptBuilder {
             .into_iter()
             .filter_map(|e| e.ok())
         {
+            if is_masked(entry.path()) {
+                debug!("Ignore `{}`. It is masked.", entry.path().display());
+                continue;
+            }
+
+            if let Some(reason) = ignored_name(entry.file_name()) {
+                debug!("Ignore `{}`. {reason}", entry.path().display());
+                continue;
+            }
+
             let metadata = match entry
                 .metadata()
                 .with_context(|| format!("Failed to get metadata of `{}`", entry.path().display()))
@@ -157,13 +464,42 @@ impl ScriptBuilder {
                 continue;
             }
 
+            if entry.path().extension() == Some(SIDECAR_EXTENSION.as_ref()) {
+                debug!(
+                    "Ignore `{}`. It is a settings file.",
+                    entry.path().display()
+                );
+                continue;
+            }
+
+            if entry.path_is_symlink()
+                && let Err(err) = fs::canonicalize(entry.path())
+                    .with_context(|| format!("Failed to resolve `{}`", entry.path().display()))
+                    .and_then(|target| {
+                        check_dir_trusted(target.parent().unwrap_or(Path::new("/")), uid)
+                            .with_context(|| format!("Its target is `{}`", target.display()))
+                    })
+            {
+                warn!("Ignore `{}`. {err:#}", entry.path().display());
+                continue;
+            }
+
             // Has at least 500 for file mode
             if metadata.mode() & 0o500 != 0o500 {
                 warn!("Ignore `{}`. It is not executable.", entry.path().display());
                 continue;
             }
 
-            if metadata.uid() != uid {
+            if metadata.mode() & UNSAFE_WRITE_BITS != 0 {
+                warn!(
+                    "Ignore `{}`. It is writable by group or others.",
+                    entry.path().display()
+                );
+                continue;
+            }
+
+            let allowed_owner = allowed_owners.contains(&metadata.uid());
+            if metadata.uid() != uid && !allowed_owner {
                 warn!(
                     "Ignore `{}`. It is not owned by uid {uid}",
                     entry.path().display()
@@ -171,7 +507,7 @@ impl ScriptBuilder {
                 continue;
             }
 
-            if metadata.gid() != gid {
+            if metadata.gid() != gid && !allowed_owner {
                 warn!(
                     "Ignore `{}`. It is not owned by gid {gid}",
                     entry.path().display()
@@ -179,7 +515,61 @@ impl ScriptBuilder {
                 continue;
             }
 
-            scripts.push(Script::builder().set_path(entry.path()));
+            // Settings of the sidecar file are trusted as much as the script itself
+            let sidecar = ScriptConfig::sidecar_path(entry.path());
+            if let Ok(sidecar_metadata) = fs::metadata(&sidecar)
+                && (![0, metadata.uid()].contains(&sidecar_metadata.uid())
+                    || sidecar_metadata.mode() & UNSAFE_WRITE_BITS != 0)
+            {
+                warn!(
+                    "Ignore `{}`. Its settings `{}` are not owned by its owner, or writable by group or others.",
+                    entry.path().display(),
+                    sidecar.display()
+                );
+                continue;
+            }
+
+            // Settings change how an approved script runs, so its sidecar file is approved too
+            let digest = match allowlist
+                .map(|allowlist| ScriptBuilder::verify_approved(allowlist, entry.path(), &sidecar))
+                .transpose()
+            {
+                Ok(digest) => digest,
+                Err(err) => {
+                    audit_refused(entry.path(), &err);
+                    continue;
+                }
+            };
+
+            let config = match ScriptConfig::from_script(entry.path()) {
+                Ok(config) => config,
+                Err(err) => {
+                    warn!("Ignore `{}`. {err:#}", entry.path().display());
+                    continue;
+                }
+            };
+
+            let run_as = if metadata.uid() == uid && metadata.gid() == gid {
+                RunAs::from_settings(config.user.as_deref(), config.group.as_deref())
+            } else {
+                ScriptBuilder::owner_run_as(metadata.uid(), &config).map(Some)
+            };
+            let run_as = match run_as {
+                Ok(run_as) => run_as,
+                Err(err) => {
+                    warn!("Ignore `{}`. {err:#}", entry.path().display());
+                    continue;
+                }
+            };
+
+            let mut script = Script::builder().set_path(entry.path()).set_config(config);
+            if let Some(digest) = digest {
+                script = script.set_digest(&digest);
+            }
+            if let Some(run_as) = run_as {
+                script = script.set_run_as(run_as);
+            }
+            scripts.push(script);
         }
 
         if scripts.is_empty() {
@@ -189,6 +579,47 @@ impl ScriptBuilder {
         Ok(scripts)
     }
 
+    /// A script of an allowed owner runs as its owner. It may only choose one of the groups of
+    /// the owner.
+    fn owner_run_as(uid: u32, config: &ScriptConfig) -> Result<RunAs> {
+        let owner = RunAs::owner(uid)?;
+        let Some(run_as) = RunAs::from_settings(
+            config.user.as_deref().or(Some(&owner.user)),
+            config.group.as_deref(),
+        )?
+        else {
+            return Ok(owner);
+        };
+        if run_as.uid != owner.uid {
+            bail!(
+                "It is owned by `{}`, it cannot run as `{}`",
+                owner.user,
+                run_as.user
+            );
+        }
+        if !owner.groups.contains(&run_as.gid) {
+            bail!(
+                "It is owned by `{}`, it cannot run as group {}",
+                owner.user,
+                run_as.gid
+            );
+        }
+        Ok(run_as)
+    }
+
+    /// Check a script and its sidecar file with the allowlist, return the digest of the script.
+    /// A sidecar file which is listed must still exist, so removing it does not change how the
+    /// script runs either. Settings in the header are approved with the script itself.
+    fn verify_approved(allowlist: &Allowlist, script: &Path, sidecar: &Path) -> Result<String> {
+        let digest = allowlist.verify(script)?;
+        if sidecar.exists() || allowlist.contains(sidecar) {
+            allowlist.verify(sidecar).with_context(|| {
+                format!("Its settings `{}` are not approved", sidecar.display())
+            })?;
+        }
+        Ok(digest)
+    }
+
     fn should_run_nowait(path: &Path) -> bool {
         path.file_stem()
             .unwrap()
@@ -198,12 +629,150 @@ impl ScriptBuilder {
     }
 }
 
+/// Why a file of a script directory is not a script, like `run-parts` which skips editor
+/// backups, leftovers of package managers and hidden files
+pub(crate) fn ignored_name(name: &OsStr) -> Option<&'static str> {
+    let name = name.to_string_lossy();
+    if name.starts_with('.') {
+        Some("It is a hidden file.")
+    } else if IGNORED_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        || name.contains(".dpkg-")
+        || name.contains(".ucf-")
+    {
+        Some("It is a backup or a leftover of a package manager.")
+    } else {
+        None
+    }
+}
+
+/// Check that a directory and every directory above it are owned by root or `uid`, and are not
+/// writable by group or others unless they are sticky, e.g. `/tmp`
+fn check_dir_trusted(dir: &Path, uid: u32) -> Result<()> {
+    let dir =
+        fs::canonicalize(dir).with_context(|| format!("Failed to resolve `{}`", dir.display()))?;
+    for ancestor in dir.ancestors() {
+        let metadata = fs::metadata(ancestor)
+            .with_context(|| format!("Failed to get metadata of `{}`", ancestor.display()))?;
+        if metadata.uid() != 0 && metadata.uid() != uid {
+            bail!(
+                "`{}` is owned by uid {}, not by uid {uid}",
+                ancestor.display(),
+                metadata.uid()
+            );
+        }
+        if metadata.mode() & UNSAFE_WRITE_BITS != 0 && metadata.mode() & STICKY_BIT == 0 {
+            bail!("`{}` is writable by group or others", ancestor.display());
+        }
+    }
+    Ok(())
+}
+
+/// Check a file which is named directly instead of found in a script directory, e.g. a hook
+/// script or the configuration file. The file and its symlink target must be in trusted
+/// directories, see `check_dir_trusted`, owned by root or `uid`, and not writable by group or
+/// others.
+pub(crate) fn check_file_trusted(path: &Path, uid: u32) -> Result<fs::Metadata> {
+    check_dir_trusted(path.parent().unwrap_or(Path::new("/")), uid)?;
+    let target = fs::canonicalize(path)
+        .with_context(|| format!("Failed to resolve `{}`", path.display()))?;
+    if target != path {
+        check_dir_trusted(target.parent().unwrap_or(Path::new("/")), uid)
+            .with_context(|| format!("Its target is `{}`", target.display()))?;
+    }
+
+    let metadata = fs::metadata(&target)
+        .with_context(|| format!("Failed to get metadata of `{}`", target.display()))?;
+    if !metadata.is_file() {
+        bail!("It is not a regular file");
+    }
+    if metadata.uid() != 0 && metadata.uid() != uid {
+        bail!("It is owned by uid {}, not by uid {uid}", metadata.uid());
+    }
+    if metadata.mode() & UNSAFE_WRITE_BITS != 0 {
+        bail!("It is writable by group or others");
+    }
+    Ok(metadata)
+}
+
+/// Whether a script is masked by a symlink to `/dev/null`, so a script of the same name in
+/// another script root directory is not run either
+fn is_masked(path: &Path) -> bool {
+    fs::read_link(path).is_ok_and(|target| target == Path::new(MASK_TARGET))
+}
+
+/// Request to stop a running script before its timeout, it is shared by clones
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Why a running script is stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    Timeout,
+    Cancel,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Timeout => write!(f, "Timeout"),
+            Stop::Cancel => write!(f, "Cancel"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Script {
     path: PathBuf,
     args: Vec<String>,
     envs: HashMap<String, String>,
     timeout: Option<u64>,
+
+    /// Signal which is sent to the script on timeout
+    kill_signal: Signal,
+
+    /// Seconds to wait after `kill_signal` before the script is killed with `SIGKILL`
+    grace: u64,
+
+    output_mode: OutputMode,
+
+    /// Relates every script which is run in response to the same network event
+    event_id: u64,
+
+    /// Index of the link which the script is run for, 0 if it is not set
+    ifindex: i32,
+
+    cancel: CancelToken,
+
+    /// User and groups which the script runs as, instead of root
+    run_as: Option<RunAs>,
+
+    /// Shell command which is run instead of `path`
+    inline: Option<String>,
+
+    /// SHA-256 digest which the script must still have when it is executed
+    digest: Option<String>,
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.path.display(),
+            self.args[0],
+            self.args[1]
+        )
+    }
 }
 
 impl Script {
@@ -212,26 +781,98 @@ impl Script {
             path: PathBuf::new(),
             arg0: String::new(),
             arg1: String::new(),
+            arg2: None,
             envs: HashMap::new(),
             default_timeout: DEFAULT_TIMEOUT,
+            default_grace: DEFAULT_GRACE,
+            output_mode: OutputMode::default(),
+            event_id: None,
+            ifindex: None,
+            run_as: None,
+            config: ScriptConfig::default(),
+            inline: None,
+            digest: None,
         }
     }
 
+    pub fn ifindex(&self) -> i32 {
+        self.ifindex
+    }
+
+    pub fn event_id(&self) -> u64 {
+        self.event_id
+    }
+
+    /// Token which stops the script while it is running, the same as on timeout. A script
+    /// which runs without waiting cannot be cancelled.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
     pub fn execute(self) -> Result<()> {
-        let mut process = match Command::new(&self.path)
+        // The script may be modified after it is approved
+        if let Some(expected) = &self.digest {
+            let digest = sha256_file(&self.path)?;
+            if digest != *expected {
+                let err = anyhow!(
+                    "Its SHA-256 digest {digest} does not match {expected} of the allowlist"
+                );
+                audit_refused(&self.path, &err);
+                bail!("Refuse to execute {self}. {err:#}");
+            }
+        }
+
+        let mut command = match &self.inline {
+            // `path` is `$0` of the command
+            Some(inline) => {
+                let mut command = Command::new(SHELL);
+                command.arg("-c").arg(inline).arg(&self.path);
+                command
+            }
+            None => Command::new(&self.path),
+        };
+        command
             .args(self.args.clone())
-            .envs(self.envs)
-            .spawn()
-            .with_context(|| {
-                format!(
-                    "Failed to execute {script} {arg0} {arg1}",
-                    script = &self.path.display(),
-                    arg0 = self.args[0],
-                    arg1 = self.args[1]
-                )
-            }) {
+            // Own process group, so every process which is spawned by the script can be
+            // terminated on timeout
+            .process_group(0)
+            .stdout(self.output_mode.stdio())
+            .stderr(self.output_mode.stdio());
+        if let Some(run_as) = self.run_as.clone() {
+            debug!("Run {self} as user {} group {}", run_as.user, run_as.gid);
+            // Environment of the broker is not passed to an unprivileged script
+            command.env_clear();
+            for key in PASSED_ENV_VARS {
+                if let Some(value) = std::env::var_os(key) {
+                    command.env(key, value);
+                }
+            }
+            command
+                .env("HOME", &run_as.home)
+                .env("USER", &run_as.user)
+                .env("LOGNAME", &run_as.user);
+            // SAFETY: `drop_privileges` only makes system calls, which are safe between fork
+            // and exec.
+            unsafe {
+                command.pre_exec(move || run_as.drop_privileges());
+            }
+        }
+        command.envs(&self.envs);
+
+        let mut process = match command.spawn().with_context(|| {
+            format!(
+                "Failed to execute {script} {arg0} {arg1}",
+                script = &self.path.display(),
+                arg0 = self.args[0],
+                arg1 = self.args[1]
+            )
+        }) {
             Ok(process) => {
                 info!(
+                    nwd_script = %self.path.display(),
+                    nwd_state = self.args[0],
+                    nwd_iface = self.args[1],
+                    nwd_event_id = self.event_id,
                     "Execute {script} {arg0} {arg1}",
                     script = &self.path.display(),
                     arg0 = self.args[0],
@@ -241,26 +882,40 @@ impl Script {
             }
             Err(err) => bail!("{err:#}"),
         };
+        let started = Instant::now();
+        let output = OutputCapture::start(
+            &mut process,
+            self.output_mode,
+            OutputSource {
+                script: self.path.display().to_string(),
+                state: self.args[0].clone(),
+                iface: self.args[1].clone(),
+                event_id: self.event_id,
+            },
+        );
 
         if let Some(timeout) = self.timeout {
-            match process
-                .wait_timeout(Duration::from_secs(timeout))
-                .context("Failed to wait until child process to finish or timeout")?
-            {
-                Some(exit_code) => {
-                    info!(
-                        "Finished executing {script} {arg0} {arg1}, {exit_code}",
+            match self.wait(&mut process, Duration::from_secs(timeout))? {
+                Ok(exit_code) => {
+                    output.finish(exit_code.success());
+                    self.log_finished(exit_code, started);
+                    return Ok(());
+                }
+                Err(Stop::Timeout) => {
+                    let exit_code = self.terminate(&mut process, started, Stop::Timeout)?;
+                    output.finish(false);
+                    bail!(
+                        "Execute timeout {script} {arg0} {arg1}, >= {timeout} seconds, {exit_code}",
                         script = &self.path.display(),
                         arg0 = self.args[0],
                         arg1 = self.args[1]
                     );
-                    return Ok(());
                 }
-                None => {
-                    process.kill()?;
-                    let exit_code = process.wait()?;
+                Err(Stop::Cancel) => {
+                    let exit_code = self.terminate(&mut process, started, Stop::Cancel)?;
+                    output.finish(false);
                     bail!(
-                        "Execute timeout {script} {arg0} {arg1}, >= {timeout} seconds, {exit_code}",
+                        "Execute cancelled {script} {arg0} {arg1}, {exit_code}",
                         script = &self.path.display(),
                         arg0 = self.args[0],
                         arg1 = self.args[1]
@@ -274,12 +929,10 @@ impl Script {
                     .wait()
                     .context("Failed to wait until child process to finish")
                 {
-                    Ok(exit_code) => info!(
-                        "Finished executing {script} {arg0} {arg1}, {exit_code}",
-                        script = &self.path.display(),
-                        arg0 = self.args[0],
-                        arg1 = self.args[1]
-                    ),
+                    Ok(exit_code) => {
+                        output.finish(exit_code.success());
+                        self.log_finished(exit_code, started);
+                    }
                     Err(err) => warn!(
                         "{script} {arg0} {arg1} wasn't running: {err:#}",
                         script = &self.path.display(),
@@ -292,6 +945,161 @@ impl Script {
 
         Ok(())
     }
+
+    fn log_finished(&self, exit_code: ExitStatus, started: Instant) {
+        info!(
+            nwd_script = %self.path.display(),
+            nwd_state = self.args[0],
+            nwd_iface = self.args[1],
+            nwd_event_id = self.event_id,
+            nwd_exit_status = exit_status_field(&exit_code),
+            nwd_duration_ms = started.elapsed().as_millis(),
+            "Finished executing {script} {arg0} {arg1}, {exit_code}",
+            script = &self.path.display(),
+            arg0 = self.args[0],
+            arg1 = self.args[1]
+        );
+    }
+
+    /// Wait for the script to exit, until timeout or cancellation
+    fn wait(&self, process: &mut Child, timeout: Duration) -> Result<Result<ExitStatus, Stop>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if let Some(exit_code) = process
+                .wait_timeout(remaining.min(CANCEL_POLL_INTERVAL))
+                .context("Failed to wait until child process to finish or timeout")?
+            {
+                return Ok(Ok(exit_code));
+            }
+            if self.cancel.is_cancelled() {
+                return Ok(Err(Stop::Cancel));
+            }
+            if Instant::now() >= deadline {
+                return Ok(Err(Stop::Timeout));
+            }
+        }
+    }
+
+    /// Stop the process group of a timed out or cancelled script with its kill signal, then
+    /// kill the group if any process is still running after the grace period
+    fn terminate(&self, process: &mut Child, started: Instant, stop: Stop) -> Result<ExitStatus> {
+        let pgid = Pid::from_raw(i32::try_from(process.id())?);
+        warn!(
+            nwd_script = %self.path.display(),
+            nwd_state = self.args[0],
+            nwd_iface = self.args[1],
+            nwd_event_id = self.event_id,
+            nwd_timeout = stop == Stop::Timeout,
+            nwd_duration_ms = started.elapsed().as_millis(),
+            "{stop} {script} {arg0} {arg1}, send {signal} to process group {pgid}: {processes}",
+            script = &self.path.display(),
+            arg0 = self.args[0],
+            arg1 = self.args[1],
+            signal = self.kill_signal,
+            processes = describe_processes(&process_group_members(pgid)),
+        );
+        killpg(pgid, self.kill_signal).with_context(|| {
+            format!(
+                "Failed to send {} to process group {pgid}",
+                self.kill_signal
+            )
+        })?;
+
+        // Wait for every process of the group, not only the script itself
+        let deadline = Instant::now() + Duration::from_secs(self.grace);
+        let mut exit_code = None;
+        loop {
+            if exit_code.is_none() {
+                exit_code = process
+                    .try_wait()
+                    .context("Failed to wait until child process to finish")?;
+            }
+            if exit_code.is_some() && killpg(pgid, None).is_err() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                let remaining = process_group_members(pgid);
+                if !remaining.is_empty() {
+                    warn!(
+                        nwd_script = %self.path.display(),
+                        nwd_state = self.args[0],
+                        nwd_iface = self.args[1],
+                        nwd_event_id = self.event_id,
+                        nwd_timeout = stop == Stop::Timeout,
+                        nwd_duration_ms = started.elapsed().as_millis(),
+                        "Kill process group {pgid} of {script} {arg0} {arg1}: {processes}",
+                        script = &self.path.display(),
+                        arg0 = self.args[0],
+                        arg1 = self.args[1],
+                        processes = describe_processes(&remaining),
+                    );
+                }
+                // The group may be gone in the meantime
+                let _ = killpg(pgid, Signal::SIGKILL);
+                break;
+            }
+            thread::sleep(TERMINATE_POLL_INTERVAL);
+        }
+
+        match exit_code {
+            Some(exit_code) => Ok(exit_code),
+            None => Ok(process.wait()?),
+        }
+    }
+}
+
+/// Exit code of a script, or name of the signal which terminates it
+fn exit_status_field(exit_code: &ExitStatus) -> String {
+    match (exit_code.code(), exit_code.signal()) {
+        (Some(code), _) => code.to_string(),
+        (None, Some(signal)) => Signal::try_from(signal)
+            .map(|signal| signal.as_str().to_string())
+            .unwrap_or_else(|_| signal.to_string()),
+        (None, None) => exit_code.to_string(),
+    }
+}
+
+/// Running processes of a process group, as pid and command name
+fn process_group_members(pgid: Pid) -> Vec<(i32, String)> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let mut processes = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .filter_map(|entry| fs::read_to_string(entry.path().join("stat")).ok())
+        .filter_map(|stat| parse_proc_stat(&stat))
+        .filter(|(_, _, state, pgrp)| *pgrp == pgid.as_raw() && *state != 'Z')
+        .map(|(pid, comm, _, _)| (pid, comm))
+        .collect::<Vec<_>>();
+    processes.sort();
+    processes
+}
+
+/// Parse pid, command name, state and process group ID from `/proc/<pid>/stat`
+fn parse_proc_stat(stat: &str) -> Option<(i32, String, char, i32)> {
+    // Command name is enclosed in parentheses and may contain spaces and parentheses
+    let (pid, rest) = stat.split_once(" (")?;
+    let (comm, rest) = rest.rsplit_once(") ")?;
+    let mut fields = rest.split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let _ppid = fields.next()?;
+    let pgrp = fields.next()?.parse().ok()?;
+    Some((pid.parse().ok()?, comm.to_string(), state, pgrp))
+}
+
+fn describe_processes(processes: &[(i32, String)]) -> String {
+    processes
+        .iter()
+        .map(|(pid, comm)| format!("{pid} ({comm})"))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 #[cfg(test)]
@@ -303,7 +1111,10 @@ mod tests {
             DirBuilder,
         },
         ops::Deref,
-        os::unix::fs::OpenOptionsExt,
+        os::unix::fs::{
+            OpenOptionsExt,
+            PermissionsExt,
+        },
     };
 
     use tempfile::TempDir;
@@ -372,6 +1183,187 @@ mod tests {
         assert_eq!(script.timeout, None);
     }
 
+    #[test]
+    fn build_new_script_with_previous_state() {
+        let script = Script::builder()
+            .set_path(Path::new("/etc/networkd/broker.d/routable.d/00-script"))
+            .set_arg0("routable")
+            .set_arg1("wlp3s0")
+            .build();
+        assert_eq!(script.args, vec!["routable", "wlp3s0"]);
+
+        let script = Script::builder()
+            .set_path(Path::new("/etc/networkd/broker.d/routable.d/00-script"))
+            .set_arg0("routable")
+            .set_arg1("wlp3s0")
+            .set_arg2("degraded")
+            .build();
+        assert_eq!(script.args, vec!["routable", "wlp3s0", "degraded"]);
+    }
+
+    #[test]
+    fn build_new_script_with_config() {
+        let script = Script::builder()
+            .set_path(Path::new("/etc/networkd/broker.d/routable.d/00-vpn.sh"))
+            .set_default_timeout(5)
+            .build();
+        assert_eq!(script.timeout, Some(5));
+        assert_eq!(script.kill_signal, DEFAULT_KILL_SIGNAL);
+        assert_eq!(script.grace, DEFAULT_GRACE);
+
+        let script = Script::builder()
+            .set_path(Path::new("/etc/networkd/broker.d/routable.d/00-vpn.sh"))
+            .set_default_timeout(5)
+            .set_config(ScriptConfig {
+                timeout: Some(120),
+                nowait: None,
+                kill_signal: Some(Signal::SIGTERM),
+                grace: Some(10),
+                ..Default::default()
+            })
+            .build();
+        assert_eq!(script.timeout, Some(120));
+        assert_eq!(script.kill_signal, Signal::SIGTERM);
+        assert_eq!(script.grace, 10);
+
+        // Settings of the script override `-nowait` suffix
+        let script = Script::builder()
+            .set_path(Path::new(
+                "/etc/networkd/broker.d/routable.d/00-script-nowait",
+            ))
+            .set_config(ScriptConfig {
+                nowait: Some(false),
+                ..Default::default()
+            })
+            .build();
+        assert_eq!(script.timeout, Some(DEFAULT_TIMEOUT));
+
+        let script = Script::builder()
+            .set_path(Path::new("/etc/networkd/broker.d/routable.d/00-script"))
+            .set_config(ScriptConfig {
+                nowait: Some(true),
+                ..Default::default()
+            })
+            .build();
+        assert_eq!(script.timeout, None);
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn execute_script_as_user() {
+        // Writable by the user which the script runs as
+        let temp_dir = TempDir::new().unwrap();
+        fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        let script_path = temp_dir.path().join("00-id");
+        fs::write(
+            &script_path,
+            "#!/usr/bin/env bash\n\
+             echo \"$(id -u) $(id -g) $NWD_DEVICE_IFACE\" > \"$(dirname \"$0\")/id\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let run_as = RunAs::from_settings(Some("65534"), None).unwrap().unwrap();
+        Script::builder()
+            .set_path(&script_path)
+            .set_arg0("routable")
+            .set_arg1("wlp3s0")
+            .add_env(EnvVar::DeviceIface("wlp3s0".to_string()))
+            .set_run_as(run_as.clone())
+            .build()
+            .execute()
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("id")).unwrap(),
+            format!("65534 {} wlp3s0\n", run_as.gid)
+        );
+    }
+
+    #[test]
+    fn execute_inline_command() {
+        let temp_dir = TempDir::new().unwrap();
+        let output = temp_dir.path().join("output");
+        Script::builder()
+            .set_path(Path::new("wifi-vpn"))
+            .set_inline(&format!("echo \"$0 $1 $2 $3\" > {}", output.display()))
+            .set_arg0("routable")
+            .set_arg1("wlp3s0")
+            .set_arg2("degraded")
+            .build()
+            .execute()
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(output).unwrap(),
+            "wifi-vpn routable wlp3s0 degraded\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_proc_stat() {
+        assert_eq!(
+            parse_proc_stat("4242 (sleep) S 4241 4241 4200 0 -1 4194304 88 0 0 0"),
+            Some((4242, "sleep".to_string(), 'S', 4241))
+        );
+        assert_eq!(
+            parse_proc_stat("4243 (a) (b c) Z 4241 4241 4200 0 -1 4194304 88 0 0 0"),
+            Some((4243, "a) (b c".to_string(), 'Z', 4241))
+        );
+        assert_eq!(parse_proc_stat("4244 sleep"), None);
+    }
+
+    #[test]
+    fn test_exit_status_field() {
+        assert_eq!(exit_status_field(&ExitStatus::from_raw(0)), "0");
+        assert_eq!(exit_status_field(&ExitStatus::from_raw(2 << 8)), "2");
+        assert_eq!(exit_status_field(&ExitStatus::from_raw(9)), "SIGKILL");
+    }
+
+    #[test]
+    fn build_new_script_with_link_details() {
+        let link_details = serde_json::from_str::<LinkDetails>(
+            r#"{
+                "OperationalState": "routable",
+                "CarrierState": "carrier",
+                "SSID": "HomeNetwork",
+                "Addresses": [
+                    { "Family": 2, "Address": [192, 168, 1, 101], "PrefixLength": 24 },
+                    { "Family": 2, "Address": [10, 0, 0, 5], "PrefixLength": 8 },
+                    { "Family": 10, "Address": [254, 128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1] }
+                ],
+                "Routes": [
+                    { "Family": 2, "Destination": [192, 168, 1, 0], "DestinationPrefixLength": 24 },
+                    { "Family": 2, "Destination": [0, 0, 0, 0], "DestinationPrefixLength": 0,
+                      "Gateway": [192, 168, 1, 1] }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let script = EnvVar::from_link_details(3, &link_details)
+            .into_iter()
+            .fold(
+                Script::builder()
+                    .set_path(Path::new("/etc/networkd/broker.d/routable.d/00-script")),
+                |script, env_var| script.add_env(env_var),
+            )
+            .build();
+        let expected = [
+            ("NWD_IFINDEX", "3"),
+            ("NWD_OPERATIONAL_STATE", "routable"),
+            ("NWD_CARRIER_STATE", "carrier"),
+            ("NWD_IPV4_ADDRS", "192.168.1.101 10.0.0.5"),
+            ("NWD_IPV6_ADDRS", "fe80::1"),
+            ("NWD_SSID", "HomeNetwork"),
+            ("NWD_GATEWAY", "192.168.1.1"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>();
+        assert_eq!(script.envs, expected);
+    }
+
     #[test]
     fn test_build_new_script_from_dir() {
         let temp_dir = setup_script_dir();
@@ -394,7 +1386,8 @@ mod tests {
         // 05-executable-nowait
         // 10-executable
         let carrier_d = broker_root.join("carrier.d");
-        let scripts = ScriptBuilder::build_from(&carrier_d, Some(uid), Some(gid)).unwrap();
+        let scripts =
+            ScriptBuilder::build_from(&carrier_d, Some(uid), Some(gid), &[], None).unwrap();
         assert_eq!(scripts.len(), 3);
         assert_eq!(
             scripts[0].path.file_name(),
@@ -411,20 +1404,153 @@ mod tests {
 
         // No script for configuring state
         let configuring_d = broker_root.join("configuring.d");
-        let result = ScriptBuilder::build_from(&configuring_d, Some(uid), Some(gid)).unwrap();
+        let result =
+            ScriptBuilder::build_from(&configuring_d, Some(uid), Some(gid), &[], None).unwrap();
         assert!(result.is_empty());
 
         // No script for root in degraded.d
         let degraded_d = broker_root.join("degraded.d");
-        let result = ScriptBuilder::build_from(&degraded_d, None, None).unwrap();
+        let result = ScriptBuilder::build_from(&degraded_d, None, None, &[], None).unwrap();
         assert!(result.is_empty());
 
         // No directory for routable state
         let routable_d = broker_root.join("routable.d");
-        let result = ScriptBuilder::build_from(&routable_d, Some(uid), Some(gid)).unwrap();
+        let result =
+            ScriptBuilder::build_from(&routable_d, Some(uid), Some(gid), &[], None).unwrap();
         assert!(result.is_empty());
     }
 
+    #[test]
+    fn build_from_rejects_untrusted_scripts() {
+        let temp_dir = TempDir::new().unwrap();
+        let (uid, gid) = (
+            nix::unistd::getuid().as_raw(),
+            nix::unistd::getgid().as_raw(),
+        );
+        let create = |path: &Path, mode: u32| {
+            fs::write(path, "#!/usr/bin/sh\n").unwrap();
+            fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+
+        let routable_d = temp_dir.path().join("routable.d");
+        let unsafe_dir = temp_dir.path().join("unsafe");
+        DirBuilder::new().create(&routable_d).unwrap();
+        DirBuilder::new().create(&unsafe_dir).unwrap();
+        fs::set_permissions(&unsafe_dir, fs::Permissions::from_mode(0o777)).unwrap();
+
+        create(&routable_d.join("00-trusted"), 0o755);
+        create(&routable_d.join("01-world-writable"), 0o757);
+        create(&routable_d.join("02-group-writable"), 0o775);
+        for backup in ["03-vpn~", ".03-vpn.swp", "03-vpn.dpkg-old", "03-vpn.pacnew"] {
+            create(&routable_d.join(backup), 0o755);
+        }
+        create(&unsafe_dir.join("04-target"), 0o755);
+        std::os::unix::fs::symlink(unsafe_dir.join("04-target"), routable_d.join("04-link"))
+            .unwrap();
+        create(&routable_d.join("05-trusted-link-target"), 0o755);
+        std::os::unix::fs::symlink(
+            routable_d.join("05-trusted-link-target"),
+            routable_d.join("05-link"),
+        )
+        .unwrap();
+        create(&routable_d.join("06-unsafe-settings"), 0o755);
+        fs::write(routable_d.join("06-unsafe-settings.conf"), "timeout=1\n").unwrap();
+        fs::set_permissions(
+            routable_d.join("06-unsafe-settings.conf"),
+            fs::Permissions::from_mode(0o666),
+        )
+        .unwrap();
+
+        let scripts =
+            ScriptBuilder::build_from(&routable_d, Some(uid), Some(gid), &[], None).unwrap();
+        assert_eq!(
+            scripts
+                .iter()
+                .map(|script| script.path.file_name().unwrap())
+                .collect::<Vec<_>>(),
+            ["00-trusted", "05-link", "05-trusted-link-target"]
+        );
+
+        // Every script of a writable directory is rejected
+        create(&unsafe_dir.join("00-trusted"), 0o755);
+        assert!(
+            ScriptBuilder::build_from(&unsafe_dir, Some(uid), Some(gid), &[], None)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn build_from_refuses_unapproved_scripts() {
+        let temp_dir = TempDir::new().unwrap();
+        let (uid, gid) = (
+            nix::unistd::getuid().as_raw(),
+            nix::unistd::getgid().as_raw(),
+        );
+        let routable_d = temp_dir.path().join("routable.d");
+        DirBuilder::new().create(&routable_d).unwrap();
+        let (approved, modified, unlisted, modified_settings, removed_settings) = (
+            routable_d.join("00-approved"),
+            routable_d.join("01-modified"),
+            routable_d.join("02-unlisted"),
+            routable_d.join("03-modified-settings"),
+            routable_d.join("04-removed-settings"),
+        );
+        for script in [
+            &approved,
+            &modified,
+            &unlisted,
+            &modified_settings,
+            &removed_settings,
+        ] {
+            fs::write(script, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let sidecars = [&approved, &modified_settings, &removed_settings]
+            .map(|script| ScriptConfig::sidecar_path(script));
+        for sidecar in &sidecars {
+            fs::write(sidecar, "timeout=10\n").unwrap();
+        }
+
+        let allowlist_file = temp_dir.path().join("allowlist");
+        fs::write(
+            &allowlist_file,
+            Allowlist::generate(
+                [
+                    &approved,
+                    &modified,
+                    &modified_settings,
+                    &removed_settings,
+                    &sidecars[0],
+                    &sidecars[1],
+                    &sidecars[2],
+                ]
+                .map(PathBuf::as_path),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let allowlist = Allowlist::load(&allowlist_file).unwrap();
+        fs::write(&modified, "#!/bin/sh\nexit 1\n").unwrap();
+        fs::write(&sidecars[1], "user=nobody\n").unwrap();
+        fs::remove_file(&sidecars[2]).unwrap();
+
+        let scripts =
+            ScriptBuilder::build_from(&routable_d, Some(uid), Some(gid), &[], Some(&allowlist))
+                .unwrap();
+        assert_eq!(
+            scripts
+                .iter()
+                .map(|script| script.path.as_path())
+                .collect::<Vec<_>>(),
+            [approved.as_path()]
+        );
+
+        // A script modified after it is indexed is refused to execute
+        fs::write(&approved, "#!/bin/sh\nexit 1\n").unwrap();
+        assert!(scripts[0].clone().build().execute().is_err());
+    }
+
     fn setup_script_dir() -> tempfile::TempDir {
         let temp_dir = TempDir::new().unwrap();
         assert!(temp_dir.path().to_owned().exists());
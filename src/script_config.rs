@@ -0,0 +1,250 @@
+//! # Settings of each script
+//!
+//! A script declares its settings in a comment header, e.g.
+//! `# networkd-broker: timeout=120s nowait=false kill-signal=SIGTERM grace=5s user=nobody`,
+//! or in a `<script>.conf` sidecar file with one `key=value` per line.
+//! Settings in the sidecar file override those in the header.
+
+use std::{
+    fs::File,
+    io::Read,
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use nix::sys::signal::Signal;
+
+/// Prefix of comment header which holds settings of a script
+const HEADER_PREFIX: &str = "networkd-broker:";
+
+/// Only the beginning of a script is searched for the header
+const HEADER_MAX_BYTES: u64 = 4096;
+
+/// Extension of sidecar file
+pub const SIDECAR_EXTENSION: &str = "conf";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptConfig {
+    /// Execution timeout in seconds
+    pub timeout: Option<u64>,
+
+    /// Run without waiting for the script to finish
+    pub nowait: Option<bool>,
+
+    /// Signal which is sent to the script on timeout
+    pub kill_signal: Option<Signal>,
+
+    /// Seconds to wait after `kill_signal` before the script is killed with `SIGKILL`
+    pub grace: Option<u64>,
+
+    /// User name or uid which the script runs as
+    pub user: Option<String>,
+
+    /// Group name or gid which the script runs as
+    pub group: Option<String>,
+}
+
+impl ScriptConfig {
+    /// Read settings of a script from its header and its sidecar file
+    pub fn from_script(path: &Path) -> Result<ScriptConfig> {
+        let mut config = ScriptConfig::default();
+
+        let mut head = Vec::new();
+        File::open(path)
+            .and_then(|file| file.take(HEADER_MAX_BYTES).read_to_end(&mut head))
+            .with_context(|| format!("Failed to read `{}`", path.display()))?;
+        if let Some(header) = ScriptConfig::find_header(&String::from_utf8_lossy(&head)) {
+            config
+                .merge_from(header.split_whitespace())
+                .with_context(|| format!("Invalid settings in header of `{}`", path.display()))?;
+        }
+
+        let sidecar = ScriptConfig::sidecar_path(path);
+        if sidecar.is_file() {
+            let content = std::fs::read_to_string(&sidecar)
+                .with_context(|| format!("Failed to read `{}`", sidecar.display()))?;
+            config
+                .merge_from(
+                    content
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#')),
+                )
+                .with_context(|| format!("Invalid settings in `{}`", sidecar.display()))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Sidecar file of a script, e.g. `00-vpn.sh.conf` of `00-vpn.sh`
+    pub fn sidecar_path(path: &Path) -> PathBuf {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(format!(".{SIDECAR_EXTENSION}"));
+        PathBuf::from(sidecar)
+    }
+
+    /// Find settings in the leading comment lines of a script
+    fn find_header(head: &str) -> Option<&str> {
+        head.lines()
+            .take_while(|line| line.starts_with('#'))
+            .find_map(|line| {
+                line.trim_start_matches('#')
+                    .trim_start()
+                    .strip_prefix(HEADER_PREFIX)
+            })
+    }
+
+    fn merge_from<'a>(&mut self, settings: impl Iterator<Item = &'a str>) -> Result<()> {
+        for setting in settings {
+            let Some((key, value)) = setting.split_once('=') else {
+                bail!("`{setting}` is not `key=value`");
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "timeout" => self.timeout = Some(parse_seconds(value)?),
+                "nowait" => {
+                    self.nowait = Some(
+                        value
+                            .parse::<bool>()
+                            .with_context(|| format!("Invalid nowait `{value}`"))?,
+                    )
+                }
+                "kill-signal" => self.kill_signal = Some(parse_signal(value)?),
+                "grace" => self.grace = Some(parse_seconds(value)?),
+                "user" if !value.is_empty() => self.user = Some(value.to_string()),
+                "group" if !value.is_empty() => self.group = Some(value.to_string()),
+                "user" | "group" => bail!("Empty {key}"),
+                _ => bail!("Unknown setting `{key}`"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse duration in seconds, e.g. `120`, `120s`, `2m` or `1h`
+pub(crate) fn parse_seconds(value: &str) -> Result<u64> {
+    let (number, multiplier) = if let Some(number) = value.strip_suffix('s') {
+        (number, 1)
+    } else if let Some(number) = value.strip_suffix('m') {
+        (number, 60)
+    } else if let Some(number) = value.strip_suffix('h') {
+        (number, 60 * 60)
+    } else {
+        (value, 1)
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .with_context(|| format!("Invalid duration `{value}`"))
+}
+
+/// Parse signal name, e.g. `SIGTERM` or `TERM`
+pub(crate) fn parse_signal(value: &str) -> Result<Signal> {
+    let name = value.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{name}")
+    };
+    Signal::from_str(&name).with_context(|| format!("Invalid kill-signal `{value}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("00-vpn.sh");
+        fs::write(
+            &script,
+            "#!/usr/bin/bash\n\
+             # Bring up VPN\n\
+             # networkd-broker: timeout=2m nowait=false kill-signal=TERM grace=5s user=nobody\n\
+             echo up\n\
+             # networkd-broker: timeout=1s\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ScriptConfig::from_script(&script).unwrap(),
+            ScriptConfig {
+                timeout: Some(120),
+                nowait: Some(false),
+                kill_signal: Some(Signal::SIGTERM),
+                grace: Some(5),
+                user: Some("nobody".to_string()),
+                group: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_sidecar() {
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("00-vpn.sh");
+        fs::write(
+            &script,
+            "#!/usr/bin/bash\n# networkd-broker: timeout=120s grace=5s\n",
+        )
+        .unwrap();
+        fs::write(
+            temp_dir.path().join("00-vpn.sh.conf"),
+            "# Override header\ntimeout=30\n\nkill-signal=SIGINT\ngroup=network\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            ScriptConfig::from_script(&script).unwrap(),
+            ScriptConfig {
+                timeout: Some(30),
+                nowait: None,
+                kill_signal: Some(Signal::SIGINT),
+                grace: Some(5),
+                user: None,
+                group: Some("network".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_no_settings() {
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("00-script");
+        fs::write(&script, "#!/usr/bin/bash\necho\n").unwrap();
+        assert_eq!(
+            ScriptConfig::from_script(&script).unwrap(),
+            ScriptConfig::default()
+        );
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let mut config = ScriptConfig::default();
+        assert!(config.merge_from(["timeout=soon"].into_iter()).is_err());
+        assert!(config.merge_from(["nowait=maybe"].into_iter()).is_err());
+        assert!(
+            config
+                .merge_from(["kill-signal=SIGFOO"].into_iter())
+                .is_err()
+        );
+        assert!(config.merge_from(["grace"].into_iter()).is_err());
+        assert!(config.merge_from(["user="].into_iter()).is_err());
+        assert!(config.merge_from(["retries=3"].into_iter()).is_err());
+        assert_eq!(config, ScriptConfig::default());
+    }
+}
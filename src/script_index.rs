@@ -0,0 +1,461 @@
+//! # Script index
+//!
+//! Scripts of every `*.d` directory under the script roots are found and validated up front, so
+//! link events are dispatched without scanning the file system. The index is rebuilt when inotify
+//! reports a change under a script root, or on reload.
+//!
+//! Script roots are layered like systemd unit directories, e.g. `/usr/lib/networkd/broker.d`,
+//! `/run/networkd/broker.d`, then `/etc/networkd/broker.d`. A script of a later root overrides
+//! the script of the same name in the same directory of an earlier one, and a symlink to
+//! `/dev/null` masks it.
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+        HashSet,
+    },
+    ffi::OsString,
+    fs,
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_io::{
+    Async,
+    Timer,
+};
+use futures_util::future;
+use nix::sys::inotify::{
+    AddWatchFlags,
+    InitFlags,
+    Inotify,
+    InotifyEvent,
+    WatchDescriptor,
+};
+use tracing::{
+    debug,
+    info,
+    warn,
+};
+use walkdir::WalkDir;
+
+use crate::{
+    allowlist::Allowlist,
+    script::{
+        ScriptBuilder,
+        ignored_name,
+    },
+    script_config::SIDECAR_EXTENSION,
+};
+
+/// Script directories are at most `<root>/<state type>/transitions/<transition>.d`
+const MAX_DEPTH: usize = 3;
+
+/// Wait for a burst of changes, e.g. an editor saving a file, to end before rescanning
+const RESCAN_DELAY: Duration = Duration::from_millis(200);
+
+/// Extension of script directories
+const SCRIPT_DIR_EXTENSION: &str = "d";
+
+pub struct ScriptIndex {
+    /// Script root directories in priority order
+    roots: Vec<PathBuf>,
+
+    /// Acceptable user and group IDs of scripts, root if it is not set
+    uid: Option<u32>,
+    gid: Option<u32>,
+
+    /// Users other than root whose scripts are accepted
+    allowed_owners: Vec<u32>,
+
+    /// Approved digests of scripts, every script is approved without it
+    allowlist: Option<Allowlist>,
+
+    /// Scripts of each directory relative to the script roots, in order
+    dirs: HashMap<PathBuf, Vec<ScriptBuilder>>,
+
+    /// Watches the script roots and their directories, none if inotify is not available
+    inotify: Option<Async<Inotify>>,
+
+    /// Names awaited in the nearest existing ancestors of missing script roots. Only the creation
+    /// of one of them is a change in such a directory.
+    ancestors: HashMap<WatchDescriptor, HashSet<OsString>>,
+}
+
+impl ScriptIndex {
+    /// Index scripts under the script roots, and start watching them
+    ///
+    /// * `uid` - Acceptable user ID of a script, see `ScriptBuilder::build_from`
+    /// * `gid` - Acceptable group ID of a script
+    ///
+    pub fn new(
+        roots: Vec<PathBuf>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        allowed_owners: Vec<u32>,
+        allowlist: Option<Allowlist>,
+    ) -> ScriptIndex {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)
+            .map_err(io::Error::from)
+            .and_then(Async::new)
+            .context("Failed to initialize inotify, scripts are only rescanned on reload");
+        let inotify = match inotify {
+            Ok(inotify) => Some(inotify),
+            Err(err) => {
+                warn!("{err:#}");
+                None
+            }
+        };
+
+        let mut index = ScriptIndex {
+            roots,
+            uid,
+            gid,
+            allowed_owners,
+            allowlist,
+            dirs: HashMap::new(),
+            inotify,
+            ancestors: HashMap::new(),
+        };
+        index.rescan();
+        index
+    }
+
+    pub fn allowlist(&self) -> Option<&Allowlist> {
+        self.allowlist.as_ref()
+    }
+
+    /// Replace the allowlist, it is applied by the next rescan
+    pub fn set_allowlist(&mut self, allowlist: Option<Allowlist>) {
+        self.allowlist = allowlist;
+    }
+
+    /// Every script of every directory
+    pub fn all_scripts(&self) -> impl Iterator<Item = &ScriptBuilder> {
+        self.dirs.values().flatten()
+    }
+
+    /// Scripts of a directory relative to the script roots, merged from every root in
+    /// alphabetical order
+    pub fn scripts(&self, dir: &Path) -> Vec<ScriptBuilder> {
+        self.dirs.get(dir).cloned().unwrap_or_default()
+    }
+
+    /// Find and validate scripts of every directory again. Invalid scripts are reported here,
+    /// instead of when they are to be run.
+    pub fn rescan(&mut self) {
+        // Scripts of each directory by name. A name which is taken by an invalid or a masked
+        // script of a later root has no script.
+        let mut merged: BTreeMap<PathBuf, BTreeMap<OsString, Option<ScriptBuilder>>> =
+            BTreeMap::new();
+
+        for root in self.roots.clone() {
+            if !self.watch_ancestor(&root) {
+                debug!("`{}` does not exist", root.display());
+                continue;
+            }
+
+            for entry in WalkDir::new(&root)
+                .max_depth(MAX_DEPTH)
+                .follow_links(true)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|entry| entry.file_type().is_dir())
+            {
+                self.watch(entry.path());
+
+                if entry.depth() == 0
+                    || entry.path().extension() != Some(SCRIPT_DIR_EXTENSION.as_ref())
+                {
+                    continue;
+                }
+                let Ok(relative) = entry.path().strip_prefix(&root) else {
+                    continue;
+                };
+                let scripts = merged.entry(relative.to_path_buf()).or_default();
+
+                for path in ScriptIndex::entries(entry.path()) {
+                    let Some(name) = path.file_name() else {
+                        continue;
+                    };
+                    if scripts.insert(name.to_os_string(), None).is_some() {
+                        debug!("`{}` overrides a script of an earlier root", path.display());
+                    }
+                }
+                match ScriptBuilder::build_from(
+                    entry.path(),
+                    self.uid,
+                    self.gid,
+                    &self.allowed_owners,
+                    self.allowlist.as_ref(),
+                )
+                .with_context(|| format!("Could not get scripts from `{}`", entry.path().display()))
+                {
+                    Ok(builders) => {
+                        for script in builders {
+                            if let Some(name) = script.path().file_name() {
+                                scripts.insert(name.to_os_string(), Some(script));
+                            }
+                        }
+                    }
+                    Err(err) => warn!("{err:#}"),
+                }
+            }
+        }
+
+        let dirs = merged
+            .into_iter()
+            .map(|(dir, scripts)| (dir, scripts.into_values().flatten().collect::<Vec<_>>()))
+            .collect::<HashMap<_, _>>();
+        info!(
+            "Found {} scripts in {} directories of {}",
+            dirs.values().map(Vec::len).sum::<usize>(),
+            dirs.len(),
+            self.roots
+                .iter()
+                .map(|root| format!("`{}`", root.display()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        self.dirs = dirs;
+    }
+
+    /// Entries of a script directory which take the place of scripts of the same name in
+    /// earlier roots, whether they are valid scripts or not. Backups and hidden files do not.
+    fn entries(dir: &Path) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension() != Some(SIDECAR_EXTENSION.as_ref())
+                    && path
+                        .file_name()
+                        .is_some_and(|name| ignored_name(name).is_none())
+                    && !path.is_dir()
+            })
+            .collect()
+    }
+
+    /// Wait until anything under the script roots is changed. It never returns if the script
+    /// roots are not watched.
+    pub async fn changed(&mut self) {
+        let Some(inotify) = &self.inotify else {
+            return future::pending().await;
+        };
+
+        loop {
+            match inotify
+                .read_with(|inotify| inotify.read_events().map_err(io::Error::from))
+                .await
+                .context("Failed to read inotify events, scripts are only rescanned on reload")
+            {
+                Ok(events) if events.iter().any(|event| self.is_change(event)) => break,
+                Ok(_) => continue,
+                Err(err) => {
+                    warn!("{err:#}");
+                    self.inotify = None;
+                    return;
+                }
+            }
+        }
+
+        // Drain the rest of the burst
+        Timer::after(RESCAN_DELAY).await;
+        while let Ok(events) = inotify.get_ref().read_events() {
+            if events.is_empty() {
+                break;
+            }
+        }
+        debug!("Scripts are changed");
+    }
+
+    /// Whether an event is a change under the script roots, rather than an unrelated entry
+    /// created in an ancestor of a missing root
+    fn is_change(&self, event: &InotifyEvent) -> bool {
+        match self.ancestors.get(&event.wd) {
+            Some(names) => event.name.as_ref().is_some_and(|name| names.contains(name)),
+            None => true,
+        }
+    }
+
+    /// Watch the nearest existing ancestor of a script root which does not exist, so the root is
+    /// scanned once it is created. Returns whether the root exists.
+    fn watch_ancestor(&mut self, root: &Path) -> bool {
+        loop {
+            if root.exists() {
+                return true;
+            }
+            let Some((dir, name)) = root
+                .ancestors()
+                .skip(1)
+                .zip(root.ancestors())
+                .find(|(dir, _)| dir.exists())
+                .and_then(|(dir, child)| Some((dir, child.file_name()?)))
+            else {
+                return false;
+            };
+            let Some(wd) = self.watch(dir) else {
+                return false;
+            };
+            self.ancestors
+                .entry(wd)
+                .or_default()
+                .insert(name.to_os_string());
+            // Look again in case it was created before the watch was added
+            if !dir.join(name).exists() {
+                return false;
+            }
+        }
+    }
+
+    fn watch(&self, dir: &Path) -> Option<WatchDescriptor> {
+        let inotify = self.inotify.as_ref()?;
+        // A directory which is watched already keeps its watch
+        inotify
+            .get_ref()
+            .add_watch(
+                dir,
+                AddWatchFlags::IN_CREATE
+                    | AddWatchFlags::IN_DELETE
+                    | AddWatchFlags::IN_MOVE
+                    | AddWatchFlags::IN_CLOSE_WRITE
+                    | AddWatchFlags::IN_ATTRIB
+                    | AddWatchFlags::IN_DELETE_SELF
+                    | AddWatchFlags::IN_MOVE_SELF,
+            )
+            .with_context(|| format!("Failed to watch `{}`", dir.display()))
+            .inspect_err(|err| warn!("{err:#}"))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+    };
+
+    use nix::unistd::{
+        getgid,
+        getuid,
+    };
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_script_index() {
+        let (uid, gid) = (getuid().as_raw(), getgid().as_raw());
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_path_buf();
+        let routable_d = root.join("routable.d");
+        let transitions_d = root.join("carrier-state/transitions/no-carrier-to-carrier.d");
+        fs::create_dir_all(&routable_d).unwrap();
+        fs::create_dir_all(&transitions_d).unwrap();
+        for script in [routable_d.join("00-vpn"), transitions_d.join("00-log")] {
+            fs::write(&script, "#!/usr/bin/sh\n").unwrap();
+            fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let mut index =
+            ScriptIndex::new(vec![root.clone()], Some(uid), Some(gid), Vec::new(), None);
+        assert_eq!(index.scripts(Path::new("routable.d")).len(), 1);
+        assert_eq!(
+            index
+                .scripts(Path::new(
+                    "carrier-state/transitions/no-carrier-to-carrier.d"
+                ))
+                .len(),
+            1
+        );
+        assert!(index.scripts(Path::new("degraded.d")).is_empty());
+
+        // A change is seen by inotify, and picked up by rescanning
+        let script = routable_d.join("10-dns");
+        fs::write(&script, "#!/usr/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        async_io::block_on(index.changed());
+        index.rescan();
+        assert_eq!(index.scripts(Path::new("routable.d")).len(), 2);
+    }
+
+    #[test]
+    fn test_missing_script_root() {
+        let (uid, gid) = (getuid().as_raw(), getgid().as_raw());
+        let temp_dir = TempDir::new().unwrap();
+        let etc = temp_dir.path().join("etc");
+        let root = etc.join("networkd/broker.d");
+
+        let mut index =
+            ScriptIndex::new(vec![root.clone()], Some(uid), Some(gid), Vec::new(), None);
+        assert!(index.scripts(Path::new("routable.d")).is_empty());
+
+        // The root is created one directory at a time
+        fs::create_dir(&etc).unwrap();
+        async_io::block_on(index.changed());
+        index.rescan();
+
+        let routable_d = root.join("routable.d");
+        fs::create_dir_all(&routable_d).unwrap();
+        let script = routable_d.join("00-vpn");
+        fs::write(&script, "#!/usr/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        async_io::block_on(index.changed());
+        index.rescan();
+        assert_eq!(index.scripts(Path::new("routable.d")).len(), 1);
+    }
+
+    #[test]
+    fn test_layered_script_roots() {
+        let (uid, gid) = (getuid().as_raw(), getgid().as_raw());
+        let temp_dir = TempDir::new().unwrap();
+        let vendor = temp_dir.path().join("usr/lib/networkd/broker.d");
+        let local = temp_dir.path().join("etc/networkd/broker.d");
+        for (root, script, mode) in [
+            (&vendor, "00-vpn", 0o755),
+            (&vendor, "10-dns", 0o755),
+            (&vendor, "20-ntp", 0o755),
+            (&vendor, "30-mail", 0o755),
+            (&local, "05-local", 0o755),
+            (&local, "10-dns", 0o755),
+            // Not executable, it still overrides the vendor script
+            (&local, "30-mail", 0o644),
+        ] {
+            let dir = root.join("routable.d");
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(script), "#!/usr/bin/sh\n").unwrap();
+            fs::set_permissions(dir.join(script), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        std::os::unix::fs::symlink("/dev/null", local.join("routable.d/20-ntp")).unwrap();
+
+        let index = ScriptIndex::new(
+            vec![vendor.clone(), local.clone()],
+            Some(uid),
+            Some(gid),
+            Vec::new(),
+            None,
+        );
+        assert_eq!(
+            index
+                .scripts(Path::new("routable.d"))
+                .iter()
+                .map(|script| script.path().to_path_buf())
+                .collect::<Vec<_>>(),
+            [
+                vendor.join("routable.d/00-vpn"),
+                local.join("routable.d/05-local"),
+                local.join("routable.d/10-dns"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! # Output of scripts
+//!
+//! Standard output and standard error of each script are captured through pipes and logged line
+//! by line, so the output is attributed to the script which writes it.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{
+        BufRead,
+        BufReader,
+        Read,
+    },
+    process::{
+        Child,
+        Stdio,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        mpsc::{
+            Receiver,
+            Sender,
+            channel,
+        },
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use clap::ValueEnum;
+use tracing::{
+    debug,
+    info,
+    warn,
+};
+
+/// How long to wait for the rest of output after a script exits. A process which is spawned
+/// in background by the script may keep the pipes open.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Longer lines are split, so a script which never writes a newline does not grow the buffer
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+
+/// Bytes of output which are kept until a script finishes in `OnFailure` mode, only the last
+/// lines are kept
+const MAX_KEPT_OUTPUT: usize = 64 * 1024;
+
+/// What to do with output of scripts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputMode {
+    /// Log every line as soon as it is written
+    #[default]
+    Forward,
+
+    /// Drop all output
+    Discard,
+
+    /// Log the output only when a script fails or times out
+    OnFailure,
+}
+
+impl OutputMode {
+    /// Standard output and standard error of a script
+    pub fn stdio(&self) -> Stdio {
+        match self {
+            OutputMode::Forward | OutputMode::OnFailure => Stdio::piped(),
+            OutputMode::Discard => Stdio::null(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// Script which writes the output
+pub struct OutputSource {
+    pub script: String,
+    pub state: String,
+    pub iface: String,
+    pub event_id: u64,
+}
+
+impl fmt::Display for OutputSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.script, self.state, self.iface)
+    }
+}
+
+/// Output of a running script which is read by background threads
+pub struct OutputCapture {
+    mode: OutputMode,
+
+    /// Script and its arguments which are prepended to every line
+    source: Arc<OutputSource>,
+
+    /// Lines which are kept until the script finishes, in `OnFailure` mode
+    lines: Arc<Mutex<KeptLines>>,
+
+    /// Each reader sends a message on end of its stream
+    done: Receiver<()>,
+    readers: usize,
+}
+
+impl OutputCapture {
+    /// Start reading standard output and standard error of a script
+    pub fn start(process: &mut Child, mode: OutputMode, source: OutputSource) -> OutputCapture {
+        let (tx, done) = channel();
+        let mut capture = OutputCapture {
+            mode,
+            source: Arc::new(source),
+            lines: Arc::new(Mutex::new(KeptLines::default())),
+            done,
+            readers: 0,
+        };
+        if let Some(stdout) = process.stdout.take() {
+            capture.spawn_reader(Stream::Stdout, stdout, tx.clone());
+        }
+        if let Some(stderr) = process.stderr.take() {
+            capture.spawn_reader(Stream::Stderr, stderr, tx);
+        }
+        capture
+    }
+
+    fn spawn_reader<R>(&mut self, stream: Stream, pipe: R, tx: Sender<()>)
+    where
+        R: Read + Send + 'static,
+    {
+        let mode = self.mode;
+        let source = Arc::clone(&self.source);
+        let lines = Arc::clone(&self.lines);
+        let spawned = thread::Builder::new()
+            .name(format!("script {stream}"))
+            .spawn(move || {
+                let mut reader = BufReader::new(pipe);
+                let mut buf = Vec::new();
+                loop {
+                    buf.clear();
+                    match reader
+                        .by_ref()
+                        .take(MAX_LINE_LENGTH)
+                        .read_until(b'\n', &mut buf)
+                    {
+                        Ok(0) => break,
+                        Ok(_) => {
+                            let line = String::from_utf8_lossy(&buf);
+                            let line = line.trim_end_matches(['\n', '\r']);
+                            match mode {
+                                OutputMode::Forward => log_line(&source, stream, line),
+                                OutputMode::OnFailure => lines.lock().unwrap().push(stream, line),
+                                OutputMode::Discard => {}
+                            }
+                        }
+                        Err(err) => {
+                            debug!("Failed to read {stream} of {source}: {err}");
+                            break;
+                        }
+                    }
+                }
+                let _ = tx.send(());
+            });
+        match spawned {
+            Ok(_) => self.readers += 1,
+            Err(err) => warn!("Could not read {stream} of {}: {err}", self.source),
+        }
+    }
+
+    /// Wait for the rest of output after the script exits, then log the kept output if the
+    /// script fails
+    pub fn finish(self, success: bool) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        for _ in 0..self.readers {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if self.done.recv_timeout(timeout).is_err() {
+                debug!(
+                    "Output of {} is still open, it is left to be read in background",
+                    self.source
+                );
+                break;
+            }
+        }
+
+        if self.mode == OutputMode::OnFailure && !success {
+            let mut kept = self.lines.lock().unwrap();
+            if kept.dropped > 0 {
+                warn!(
+                    nwd_script = self.source.script,
+                    nwd_state = self.source.state,
+                    nwd_iface = self.source.iface,
+                    nwd_event_id = self.source.event_id,
+                    "{}: {} earlier lines of output are dropped, only the last {MAX_KEPT_OUTPUT} bytes are kept",
+                    self.source,
+                    kept.dropped
+                );
+            }
+            for (stream, line) in kept.lines.drain(..) {
+                log_line(&self.source, stream, &line);
+            }
+        }
+    }
+}
+
+/// The last lines of output which fit in `MAX_KEPT_OUTPUT` bytes
+#[derive(Debug, Default)]
+struct KeptLines {
+    lines: VecDeque<(Stream, String)>,
+
+    /// Bytes of kept lines, including their newlines
+    bytes: usize,
+
+    /// Number of earlier lines which are dropped
+    dropped: usize,
+}
+
+impl KeptLines {
+    fn push(&mut self, stream: Stream, line: &str) {
+        self.lines.push_back((stream, line.to_string()));
+        self.bytes += line.len() + 1;
+        while self.bytes > MAX_KEPT_OUTPUT
+            && let Some((_, line)) = self.lines.pop_front()
+        {
+            self.bytes -= line.len() + 1;
+            self.dropped += 1;
+        }
+    }
+}
+
+fn log_line(source: &OutputSource, stream: Stream, line: &str) {
+    match stream {
+        Stream::Stdout => info!(
+            nwd_script = source.script,
+            nwd_state = source.state,
+            nwd_iface = source.iface,
+            nwd_event_id = source.event_id,
+            nwd_stream = %stream,
+            "{source} [{stream}]: {line}"
+        ),
+        Stream::Stderr => warn!(
+            nwd_script = source.script,
+            nwd_state = source.state,
+            nwd_iface = source.iface,
+            nwd_event_id = source.event_id,
+            nwd_stream = %stream,
+            "{source} [{stream}]: {line}"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kept_lines() {
+        let mut kept = KeptLines::default();
+        kept.push(Stream::Stdout, "first");
+        kept.push(Stream::Stderr, "second");
+        assert_eq!(kept.bytes, 13);
+        assert_eq!(kept.dropped, 0);
+
+        // Only the last lines are kept
+        let line = "x".repeat(1023);
+        for _ in 0..MAX_KEPT_OUTPUT / 1024 {
+            kept.push(Stream::Stdout, &line);
+        }
+        assert_eq!(kept.lines.len(), MAX_KEPT_OUTPUT / 1024);
+        assert_eq!(kept.bytes, MAX_KEPT_OUTPUT);
+        assert_eq!(kept.dropped, 2);
+
+        kept.push(Stream::Stderr, "last");
+        assert_eq!(kept.dropped, 3);
+        assert_eq!(
+            kept.lines.back(),
+            Some(&(Stream::Stderr, "last".to_string()))
+        );
+        assert!(kept.bytes <= MAX_KEPT_OUTPUT);
+    }
+}
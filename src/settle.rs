@@ -0,0 +1,248 @@
+//! # Settle time of link states
+//!
+//! A changed link state has to persist for its settle time before its scripts are run. A state
+//! which changes again in the meantime restarts the settle time, so the scripts see only the net
+//! transition, e.g. `routable -> degraded -> routable` of a DHCP lease renewal is not a change.
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    str::FromStr,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::{
+    Error,
+    Result,
+};
+
+use crate::{
+    link::StateType,
+    script_config::parse_seconds,
+};
+
+/// A `--settle` argument, `<duration>` for every state or `<state>=<duration>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettleArg {
+    pub state: Option<String>,
+    pub delay: Duration,
+}
+
+impl FromStr for SettleArg {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (state, delay) = match value.split_once('=') {
+            Some((state, delay)) => (Some(state.trim().to_string()), delay),
+            None => (None, value),
+        };
+        Ok(SettleArg {
+            state,
+            delay: Duration::from_secs(parse_seconds(delay.trim())?),
+        })
+    }
+}
+
+/// Settle time of each state
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SettleTimes {
+    default: Duration,
+    states: HashMap<String, Duration>,
+}
+
+impl SettleTimes {
+    /// Settle times from `--settle` arguments, a later argument overrides an earlier one
+    pub fn new(args: &[SettleArg]) -> SettleTimes {
+        let mut times = SettleTimes::default();
+        for arg in args {
+            match &arg.state {
+                Some(state) => {
+                    times.states.insert(state.clone(), arg.delay);
+                }
+                None => times.default = arg.delay,
+            }
+        }
+        times
+    }
+
+    pub fn delay(&self, state: &str) -> Duration {
+        self.states.get(state).copied().unwrap_or(self.default)
+    }
+}
+
+/// State changes which are waiting for their settle time, keyed by ifindex and state type
+#[derive(Debug, Default)]
+pub struct Settling {
+    times: SettleTimes,
+    pending: BTreeMap<(i32, StateType), Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    /// The state which scripts are last run for
+    previous_state: Option<String>,
+    deadline: Instant,
+}
+
+impl Settling {
+    pub fn new(times: SettleTimes) -> Settling {
+        Settling {
+            times,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Start settle time of a changed state, or restart it if the state is already settling.
+    /// Return `false` if the change is to be responded to right away.
+    pub fn settle(
+        &mut self,
+        index: i32,
+        state_type: StateType,
+        previous_state: Option<String>,
+        state: &str,
+        now: Instant,
+    ) -> bool {
+        let deadline = now + self.times.delay(state);
+        match self.pending.get_mut(&(index, state_type)) {
+            Some(pending) => pending.deadline = deadline,
+            None if deadline == now => return false,
+            None => {
+                self.pending.insert(
+                    (index, state_type),
+                    Pending {
+                        previous_state,
+                        deadline,
+                    },
+                );
+            }
+        }
+        true
+    }
+
+    /// When the next settle time ends
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.deadline).min()
+    }
+
+    /// Take states whose settle time has ended, with the states which scripts are last run for
+    pub fn take_settled(&mut self, now: Instant) -> Vec<(i32, StateType, Option<String>)> {
+        let settled: Vec<(i32, StateType)> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        settled
+            .into_iter()
+            .filter_map(|key| {
+                self.pending
+                    .remove(&key)
+                    .map(|pending| (key.0, key.1, pending.previous_state))
+            })
+            .collect()
+    }
+
+    /// Stop settling a state, return the state which scripts are last run for
+    pub fn cancel(&mut self, index: i32, state_type: StateType) -> Option<Option<String>> {
+        self.pending
+            .remove(&(index, state_type))
+            .map(|pending| pending.previous_state)
+    }
+
+    /// Stop settling every state of a link which is gone
+    pub fn remove_link(&mut self, index: i32) {
+        self.pending
+            .retain(|(pending_index, _), _| *pending_index != index);
+    }
+
+    /// Settling states of a link, with the states which scripts are last run for
+    pub fn previous_states(&self, index: i32) -> impl Iterator<Item = (StateType, Option<&str>)> {
+        self.pending
+            .iter()
+            .filter(move |((pending_index, _), _)| *pending_index == index)
+            .map(|((_, state_type), pending)| (*state_type, pending.previous_state.as_deref()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settle_times() {
+        let args = ["2", "degraded=10s", "routable=0", "1m"]
+            .into_iter()
+            .map(|arg| arg.parse::<SettleArg>().unwrap())
+            .collect::<Vec<_>>();
+        let times = SettleTimes::new(&args);
+        assert_eq!(times.delay("degraded"), Duration::from_secs(10));
+        assert_eq!(times.delay("routable"), Duration::ZERO);
+        assert_eq!(times.delay("no-carrier"), Duration::from_secs(60));
+
+        assert!("soon".parse::<SettleArg>().is_err());
+        assert!("degraded=soon".parse::<SettleArg>().is_err());
+    }
+
+    #[test]
+    fn test_settling() {
+        let times = SettleTimes::new(&["degraded=5".parse().unwrap()]);
+        let mut settling = Settling::new(times);
+        let now = Instant::now();
+
+        // No settle time
+        assert!(!settling.settle(
+            3,
+            StateType::Carrier,
+            Some("no-carrier".to_string()),
+            "carrier",
+            now
+        ));
+        assert!(settling.is_empty());
+
+        // routable -> degraded -> routable
+        assert!(settling.settle(
+            3,
+            StateType::Operational,
+            Some("routable".to_string()),
+            "degraded",
+            now
+        ));
+        assert_eq!(settling.next_deadline(), Some(now + Duration::from_secs(5)));
+        assert!(settling.take_settled(now).is_empty());
+
+        // A settling state restarts its settle time with the delay of the new state, and keeps
+        // the state which scripts are last run for
+        let later = now + Duration::from_secs(1);
+        assert!(settling.settle(
+            3,
+            StateType::Operational,
+            Some("degraded".to_string()),
+            "routable",
+            later
+        ));
+        assert_eq!(
+            settling.previous_states(3).collect::<Vec<_>>(),
+            [(StateType::Operational, Some("routable"))]
+        );
+        assert_eq!(
+            settling.take_settled(later),
+            [(3, StateType::Operational, Some("routable".to_string()))]
+        );
+        assert!(settling.is_empty());
+
+        assert!(settling.settle(4, StateType::Operational, None, "degraded", now));
+        assert_eq!(settling.cancel(4, StateType::Operational), Some(None));
+        assert!(settling.settle(4, StateType::Operational, None, "degraded", now));
+        settling.remove_link(4);
+        assert!(settling.is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    env,
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    Context,
+    Result,
+};
+use serde::{
+    Serialize,
+    de::DeserializeOwned,
+};
+use tracing::debug;
+
+pub const DEFAULT_RUNTIME_DIR: &str = "/run/networkd-broker";
+const STATE_FILE_NAME: &str = "link-state-cache.json";
+
+/// A file which keeps broker's state across restarts
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+
+    /// Content of the latest save, used to skip saving unchanged state
+    saved: Option<String>,
+}
+
+impl StateFile {
+    pub fn new(path: &Path) -> StateFile {
+        StateFile {
+            path: path.to_path_buf(),
+            saved: None,
+        }
+    }
+
+    /// State file in `$RUNTIME_DIRECTORY`, which is set by systemd, or in `/run/networkd-broker`
+    pub fn in_runtime_dir() -> StateFile {
+        let runtime_dir = env::var_os("RUNTIME_DIRECTORY")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_RUNTIME_DIR));
+        StateFile::new(&runtime_dir.join(STATE_FILE_NAME))
+    }
+
+    /// Load state from file. Return `None` if there is no state file.
+    pub fn load<T: DeserializeOwned>(&self) -> Result<Option<T>> {
+        if !self.path.exists() {
+            debug!("`{}` does not exist", self.path.display());
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read `{}`", self.path.display()))?;
+        let state = serde_json::from_str::<T>(&content)
+            .with_context(|| format!("Failed to parse `{}`", self.path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Save state to file, if it is changed since the latest save.
+    /// The file is replaced atomically, so a crash during saving does not leave a partial file.
+    pub fn save<T: Serialize>(&mut self, state: &T) -> Result<()> {
+        let content = serde_json::to_string(state).context("Failed to serialize state")?;
+        if self.saved.as_ref() == Some(&content) {
+            return Ok(());
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create `{}`", dir.display()))?;
+        }
+
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, &content)
+            .with_context(|| format!("Failed to write `{}`", temp_path.display()))?;
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Failed to replace `{}`", self.path.display()))?;
+
+        debug!("Saved state to `{}`", self.path.display());
+        self.saved = Some(content);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir
+            .path()
+            .join("run/networkd-broker")
+            .join(STATE_FILE_NAME);
+
+        let mut state_file = StateFile::new(&path);
+        assert!(
+            state_file
+                .load::<BTreeMap<i32, String>>()
+                .unwrap()
+                .is_none()
+        );
+
+        let state = BTreeMap::from([(1, "lo".to_string()), (3, "wlp3s0".to_string())]);
+        state_file.save(&state).unwrap();
+        assert_eq!(
+            state_file.load::<BTreeMap<i32, String>>().unwrap(),
+            Some(state.clone())
+        );
+
+        // A new instance reads state which is saved by another one
+        let state_file = StateFile::new(&path);
+        assert_eq!(
+            state_file.load::<BTreeMap<i32, String>>().unwrap(),
+            Some(state)
+        );
+    }
+}
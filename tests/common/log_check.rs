@@ -7,6 +7,7 @@ use std::{
     thread,
 };
 
+use networkd_broker::journal::HideJournalFields;
 use tempfile::NamedTempFile;
 use tracing_subscriber::EnvFilter;
 
@@ -15,6 +16,7 @@ pub(crate) fn setup_log() -> File {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::new("networkd_broker=info"))
         .without_time()
+        .fmt_fields(HideJournalFields)
         .with_writer(log_file.reopen().unwrap())
         .init();
     log_file.reopen().unwrap()
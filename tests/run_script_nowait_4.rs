@@ -56,6 +56,13 @@ fn missing_nwd_broker_action() {
             script_path.display()
         )
     );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} {STATE} {IFACE} [stderr]: FAKE-SCRIPT-ERROR: 'NWD_BROKER_ACTION' environment variable does not exist.\n",
+            script_path.display()
+        )
+    );
     assert_eq!(
         next_log(&mut reader),
         format!(
@@ -54,6 +54,13 @@ fn missing_nwd_json() {
             script_path.display()
         )
     );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} {STATE} {IFACE} [stderr]: FAKE-SCRIPT-ERROR: 'NWD_JSON' environment variable does not exist.\n",
+            script_path.display()
+        )
+    );
     assert_eq!(
         next_log(&mut reader),
         format!(
@@ -60,6 +60,13 @@ fn script_execution_timeout() {
     );
     assert!(ret.is_ok(), "Script execution timeout");
     thread::sleep(std::time::Duration::from_secs(3));
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} routable wlp3s0 [stderr]: FAKE-SCRIPT-ERROR: CASE 3 => Simulate script nowait...\n",
+            script_path.display()
+        )
+    );
     assert_eq!(
         next_log(&mut reader),
         format!(
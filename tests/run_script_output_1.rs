@@ -0,0 +1,72 @@
+use std::{
+    io::{
+        BufReader,
+        Seek,
+    },
+    path::Path,
+};
+
+use networkd_broker::{
+    script::{
+        EnvVar,
+        Script,
+    },
+    script_output::OutputMode,
+};
+
+use crate::common::{
+    IFACE,
+    STATE,
+    log_check::{
+        next_log,
+        setup_log,
+    },
+};
+
+mod common;
+
+// Output of succeeded script is dropped in on-failure mode
+#[test]
+fn output_on_failure_of_succeeded_script() {
+    let mut log_file = setup_log();
+    log_file.seek(std::io::SeekFrom::End(0)).unwrap();
+    let mut reader = BufReader::new(log_file);
+
+    let script_path = Path::new(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests",
+        "/scripts",
+        "/script-execute-test.sh"
+    ));
+
+    let script = Script::builder()
+        .set_path(script_path)
+        .set_arg0(STATE)
+        .set_arg1(IFACE)
+        .add_env(EnvVar::DeviceIface(IFACE.to_string()))
+        .add_env(EnvVar::BrokerAction(STATE.to_string()))
+        .add_env(EnvVar::Json("".to_string()))
+        .add_env(EnvVar::Custom {
+            key: "SCRIPT_TEST_CASE".to_string(),
+            value: "3".to_string(),
+        })
+        .set_output_mode(OutputMode::OnFailure)
+        .build();
+    let ret = script.execute();
+    assert!(ret.is_ok(), "Script failed");
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " INFO networkd_broker::script: Execute {} {STATE} {IFACE}\n",
+            script_path.display()
+        )
+    );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " INFO networkd_broker::script: Finished executing {} {STATE} {IFACE}, exit status: 0\n",
+            script_path.display()
+        )
+    );
+    assert_eq!(next_log(&mut reader), "");
+}
@@ -0,0 +1,68 @@
+use std::{
+    io::{
+        BufReader,
+        Seek,
+    },
+    path::Path,
+};
+
+use networkd_broker::{
+    script::Script,
+    script_output::OutputMode,
+};
+
+use crate::common::{
+    IFACE,
+    log_check::{
+        next_log,
+        setup_log,
+    },
+};
+
+mod common;
+
+// Output of failed script is kept in on-failure mode
+#[test]
+fn output_on_failure_of_failed_script() {
+    let mut log_file = setup_log();
+    log_file.seek(std::io::SeekFrom::End(0)).unwrap();
+    let mut reader = BufReader::new(log_file);
+
+    let script_path = Path::new(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests",
+        "/scripts",
+        "/script-execute-test.sh"
+    ));
+
+    let script = Script::builder()
+        .set_path(script_path)
+        .set_arg0("wrong-arg0")
+        .set_arg1(IFACE)
+        .set_output_mode(OutputMode::OnFailure)
+        .build();
+    let ret = script.execute();
+    assert!(ret.is_ok(), "Wrong argument 1");
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " INFO networkd_broker::script: Execute {} wrong-arg0 {IFACE}\n",
+            script_path.display()
+        )
+    );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} wrong-arg0 {IFACE} [stderr]: FAKE-SCRIPT-ERROR: Incorrect 1st argument => wrong-arg0\n",
+            script_path.display()
+        )
+    );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " INFO networkd_broker::script: Finished executing {} wrong-arg0 {IFACE}, exit status: 52\n",
+            script_path.display()
+        )
+    );
+    assert_eq!(next_log(&mut reader), "");
+}
@@ -46,6 +46,13 @@ fn wrong_arg_1() {
             script_path.display()
         )
     );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} wrong-arg0 {IFACE} [stderr]: FAKE-SCRIPT-ERROR: Incorrect 1st argument => wrong-arg0\n",
+            script_path.display()
+        )
+    );
     assert_eq!(
         next_log(&mut reader),
         format!(
@@ -46,6 +46,13 @@ fn wrong_arg_2() {
             script_path.display()
         )
     );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} {STATE} wrong-arg1 [stderr]: FAKE-SCRIPT-ERROR: Incorrect 2nd argument => wrong-arg1\n",
+            script_path.display()
+        )
+    );
     assert_eq!(
         next_log(&mut reader),
         format!(
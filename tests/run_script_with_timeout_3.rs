@@ -47,6 +47,13 @@ fn missing_nwd_device_iface() {
             script_path.display()
         )
     );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} {STATE} {IFACE} [stderr]: FAKE-SCRIPT-ERROR: 'NWD_DEVICE_IFACE' environment variable does not exist.\n",
+            script_path.display()
+        )
+    );
     assert_eq!(
         next_log(&mut reader),
         format!(
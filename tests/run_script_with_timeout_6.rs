@@ -57,6 +57,17 @@ fn script_failed() {
             script_path.display()
         )
     );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} {STATE} {IFACE} [stderr]: FAKE-SCRIPT-ERROR: CASE 1 => Simulate script failure...\n",
+            script_path.display()
+        )
+    );
+    assert!(next_log(&mut reader).starts_with(&format!(
+        " WARN networkd_broker::script_output: {} {STATE} {IFACE} [stderr]: ",
+        script_path.display()
+    )));
     assert_eq!(
         next_log(&mut reader),
         format!(
@@ -57,13 +57,24 @@ fn script_execution_timeout() {
             script_path.display()
         )
     );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} routable wlp3s0 [stderr]: FAKE-SCRIPT-ERROR: CASE 2 => Simulate script timeout...\n",
+            script_path.display()
+        )
+    );
     assert!(ret.is_err(), "Script execution timeout");
     assert_eq!(
         format!("{}", ret.unwrap_err().root_cause()),
         format!(
-            "Execute timeout {} routable wlp3s0, >= 2 seconds, signal: 9 (SIGKILL)",
+            "Execute timeout {} routable wlp3s0, >= 2 seconds, signal: 15 (SIGTERM)",
             script_path.display()
         )
     );
+    assert!(next_log(&mut reader).starts_with(&format!(
+        " WARN networkd_broker::script: Timeout {} routable wlp3s0, send SIGTERM to process group ",
+        script_path.display()
+    )));
     assert_eq!(next_log(&mut reader), "");
 }
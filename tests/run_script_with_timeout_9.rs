@@ -0,0 +1,123 @@
+use std::{
+    fs,
+    io::{
+        BufReader,
+        Seek,
+    },
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use networkd_broker::script::{
+    EnvVar,
+    Script,
+};
+use tempfile::NamedTempFile;
+
+use crate::common::{
+    IFACE,
+    STATE,
+    log_check::{
+        next_log,
+        setup_log,
+    },
+};
+
+mod common;
+
+// Script execution timeout, kill child process which ignores SIGTERM after grace period.
+#[test]
+fn script_execution_timeout_kill_process_group() {
+    let mut log_file = setup_log();
+    log_file.seek(std::io::SeekFrom::End(0)).unwrap();
+    let mut reader = BufReader::new(log_file);
+
+    let script_path = Path::new(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests",
+        "/scripts",
+        "/script-execute-test.sh"
+    ));
+    let pid_file = NamedTempFile::new().unwrap();
+
+    let script = Script::builder()
+        .set_path(script_path)
+        .set_arg0(STATE)
+        .set_arg1(IFACE)
+        .add_env(EnvVar::DeviceIface(IFACE.to_string()))
+        .add_env(EnvVar::BrokerAction(STATE.to_string()))
+        .add_env(EnvVar::Json("".to_string()))
+        .add_env(EnvVar::Custom {
+            key: "SCRIPT_TEST_CASE".to_string(),
+            value: "4".to_string(),
+        })
+        .add_env(EnvVar::Custom {
+            key: "PID_FILE".to_string(),
+            value: pid_file.path().display().to_string(),
+        })
+        .set_default_timeout(2)
+        .set_default_grace(1)
+        .build();
+    let ret = script.execute();
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " INFO networkd_broker::script: Execute {} routable wlp3s0\n",
+            script_path.display()
+        )
+    );
+    assert_eq!(
+        next_log(&mut reader),
+        format!(
+            " WARN networkd_broker::script_output: {} routable wlp3s0 [stderr]: FAKE-SCRIPT-ERROR: CASE 4 => Simulate script timeout with a child process ignoring SIGTERM...\n",
+            script_path.display()
+        )
+    );
+    assert!(ret.is_err(), "Script execution timeout");
+    assert_eq!(
+        format!("{}", ret.unwrap_err().root_cause()),
+        format!(
+            "Execute timeout {} routable wlp3s0, >= 2 seconds, signal: 15 (SIGTERM)",
+            script_path.display()
+        )
+    );
+
+    let child_pid = fs::read_to_string(pid_file.path()).unwrap();
+    let child_pid = child_pid.trim();
+    let log = next_log(&mut reader);
+    assert!(
+        log.starts_with(&format!(
+            " WARN networkd_broker::script: Timeout {} routable wlp3s0, send SIGTERM to process group ",
+            script_path.display()
+        )),
+        "{log}"
+    );
+    assert!(log.contains(&format!("{child_pid} (sleep)")), "{log}");
+    let log = next_log(&mut reader);
+    assert!(
+        log.starts_with(" WARN networkd_broker::script: Kill process group "),
+        "{log}"
+    );
+    assert!(
+        log.ends_with(&format!(
+            " of {} routable wlp3s0: {child_pid} (sleep)\n",
+            script_path.display()
+        )),
+        "{log}"
+    );
+    assert_eq!(next_log(&mut reader), "");
+
+    // Child process is killed, it may remain as a zombie until it is reaped by its new parent.
+    let is_killed = || {
+        let stat = fs::read_to_string(format!("/proc/{child_pid}/stat")).unwrap_or_default();
+        stat.is_empty() || stat.contains(") Z ")
+    };
+    for _ in 0..10 {
+        if is_killed() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(is_killed(), "Child process {child_pid} is still running");
+}
@@ -51,4 +51,15 @@ if [[ "$NWD_SCRIPT_TEST_CASE" == "3" ]]; then
     exit 0
 fi
 
+if [[ "$NWD_SCRIPT_TEST_CASE" == "4" ]]; then
+    echo "FAKE-SCRIPT-ERROR: CASE 4 => Simulate script timeout with a child process ignoring SIGTERM..." >&2
+    (
+        trap '' TERM
+        exec sleep 60
+    ) &
+    echo "$!" >"$NWD_PID_FILE"
+    sleep 60
+    exit 0
+fi
+
 exit 0